@@ -1,32 +1,98 @@
 pub mod admin;
+mod attachments;
+mod color;
 pub mod controls;
+pub mod filters;
+pub mod history;
+pub mod library;
+mod limits;
+mod loudness;
+pub mod panel;
 pub mod playback;
+pub mod playlists;
+pub mod queue;
+pub mod radio;
+mod sources;
 
 use chrono::Utc;
+use reqwest::Client;
 use rgb::RGB;
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
 use tokio::sync::Mutex;
+use url::Url;
 
 use crate::serenity::async_trait;
-use poise::serenity_prelude::prelude::TypeMapKey;
+use poise::serenity_prelude::prelude::{TypeMap, TypeMapKey};
 use poise::{
     send_application_reply,
     serenity_prelude::{
-        Channel, ChannelId, CreateEmbed, CreateEmbedFooter, CreateMessage, GuildId, Http,
+        Cache, ChannelId, ComponentInteractionCollector, CreateActionRow, CreateButton,
+        CreateEmbed, CreateEmbedFooter, CreateInteractionResponse,
+        CreateInteractionResponseMessage, GuildId, Http, Member, UserId,
     },
-    CreateReply,
+    ChoiceParameter, CreateReply,
+};
+use songbird::{
+    input::{AuxMetadata, Compose, File, HttpRequest, YoutubeDl},
+    tracks::{PlayMode, TrackHandle, TrackState},
+    Call, Event, EventContext, EventHandler, Songbird,
 };
-use songbird::{input::AuxMetadata, Call, Event, EventContext, EventHandler, Songbird};
 
 use crate::{
     commands::music::{
         admin::admin,
-        controls::{now_playing, skip},
+        controls::{filter, loop_mode, now_playing, pause, resume, seek, skip, volume},
+        filters::{FilterPreset, FilteredInput},
+        history::{history, previous, replay},
+        library::{display_url, library, local_path},
+        loudness::Normalize,
+        panel::{refresh_panel, show_panel, Panel, PanelCleanup, PanelProgress},
         playback::play,
+        playlists::playlist,
+        queue::queue,
+        radio::{radio, LiveStream, RadioInput, StreamTitles},
+        sources::is_direct,
     },
-    Context, Error,
+    data::{Database, HistoryEntry, MusicSettings, SavedQueue, SavedQueueTrack, SavedTrack},
+    local_get, Context, Data, Error,
 };
 
+static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ChoiceParameter)]
+pub enum LoopMode {
+    #[default]
+    #[name = "off"]
+    Off,
+    #[name = "track"]
+    Track,
+    #[name = "queue"]
+    Queue,
+}
+
+/// state for a guild's music session that isn't tied to a single track
+#[derive(Debug, Default)]
+pub struct MusicSession {
+    pub loop_mode: LoopMode,
+    pub filter: FilterPreset,
+    pub panel: Option<Panel>,
+    /// how many tracks back `/music previous` has gone. it starts over once a track finishes
+    pub history_cursor: usize,
+    /// set once someone moves a track by hand, so fair queueing leaves their order alone until
+    /// the queue runs out
+    pub arranged: bool,
+}
+
+pub type MusicSessions = Mutex<HashMap<GuildId, MusicSession>>;
+
 struct QuickLeave;
 
 impl TypeMapKey for QuickLeave {
@@ -41,6 +107,7 @@ impl TypeMapKey for SkipVotes {
 
 #[derive(Clone)]
 struct TrackRequester {
+    id: UserId,
     name: String,
     avatar_url: String,
 }
@@ -55,7 +122,124 @@ impl TypeMapKey for TrackMetadata {
     type Value = AuxMetadata;
 }
 
-#[poise::command(slash_command, subcommands("now_playing", "skip", "play", "admin"))]
+/// marks a track that doesn't count as played, because it was swapped out for a fresh copy of
+/// itself or `/music previous` went back from it
+struct Restarted;
+
+impl TypeMapKey for Restarted {
+    type Value = Self;
+}
+
+/// the url a track was requested with, so it can be recreated later
+struct TrackSource;
+
+impl TypeMapKey for TrackSource {
+    type Value = Url;
+}
+
+/// marks a track that's an audio file streamed straight from its url, rather than a page that
+/// yt-dlp has to find the audio in
+struct DirectSource;
+
+impl TypeMapKey for DirectSource {
+    type Value = Self;
+}
+
+/// a track that's about to be queued
+#[derive(Clone)]
+struct PendingTrack {
+    url: Url,
+    metadata: AuxMetadata,
+    /// whether it's an internet radio station, which gets streamed along with its titles
+    radio: bool,
+    /// whether the url is an audio file, which can be streamed without yt-dlp
+    direct: bool,
+}
+
+impl PendingTrack {
+    const fn new(url: Url, metadata: AuxMetadata) -> Self {
+        Self {
+            url,
+            metadata,
+            radio: false,
+            direct: false,
+        }
+    }
+
+    const fn direct(url: Url, metadata: AuxMetadata) -> Self {
+        Self {
+            url,
+            metadata,
+            radio: false,
+            direct: true,
+        }
+    }
+
+    const fn station(url: Url, metadata: AuxMetadata) -> Self {
+        Self {
+            url,
+            metadata,
+            radio: true,
+            direct: false,
+        }
+    }
+
+    /// turns a track from the database back into something that can be queued
+    fn from_saved(saved: &SavedTrack) -> Option<Self> {
+        let (url, metadata) = saved.to_track()?;
+
+        Some(Self {
+            url,
+            metadata,
+            radio: saved.radio,
+            direct: saved.direct,
+        })
+    }
+
+    /// what a queued track was queued as, from its typemap
+    fn from_typemap(typemap: &TypeMap) -> Option<Self> {
+        Some(Self {
+            url: typemap.get::<TrackSource>()?.clone(),
+            metadata: typemap.get::<TrackMetadata>()?.clone(),
+            radio: typemap.contains_key::<LiveStream>(),
+            direct: typemap.contains_key::<DirectSource>(),
+        })
+    }
+
+    fn to_saved(&self) -> SavedTrack {
+        SavedTrack::new(&self.url, &self.metadata, self.radio, self.direct)
+    }
+}
+
+/// how much a track is turned up or down on top of the volume, to normalize its loudness
+struct TrackGain;
+
+impl TypeMapKey for TrackGain {
+    type Value = f32;
+}
+
+#[poise::command(
+    slash_command,
+    subcommands(
+        "now_playing",
+        "skip",
+        "previous",
+        "pause",
+        "resume",
+        "seek",
+        "volume",
+        "loop_mode",
+        "filter",
+        "play",
+        "queue",
+        "playlist",
+        "history",
+        "replay",
+        "library",
+        "radio",
+        "admin"
+    )
+)]
 #[allow(clippy::unused_async)]
 pub async fn music(_: Context<'_>) -> Result<(), Error> {
     Ok(())
@@ -74,6 +258,339 @@ async fn get_client(ctx: &Context<'_>) -> Arc<Songbird> {
     }
 }
 
+/// gets the call the bot is in, but only if the author is in the same voice channel.
+/// if they aren't, this replies with the reason and returns `None`. this defers the response.
+async fn get_user_call(ctx: &Context<'_>) -> Result<Option<Arc<Mutex<Call>>>, Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let (guild_id, channel) = {
+        let guild = ctx.guild().expect("this is supposed to be guild only");
+        (
+            guild.id,
+            guild
+                .voice_states
+                .get(&ctx.author().id)
+                .and_then(|v| v.channel_id),
+        )
+    };
+
+    let Some(current_channel) = channel else {
+        send_application_reply(
+            *ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_usernotinvc",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(None);
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let manager = get_client(ctx).await;
+
+    let Some(handler_lock) = manager.get(guild_id) else {
+        send_application_reply(
+            *ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_botnotinvc",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(None);
+    };
+
+    let in_channel = handler_lock
+        .lock()
+        .await
+        .current_channel()
+        .is_some_and(|c| current_channel == c.0.get());
+
+    if in_channel {
+        Ok(Some(handler_lock))
+    } else {
+        send_application_reply(
+            *ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_notwithbot",
+                locale,
+            )),
+        )
+        .await?;
+
+        Ok(None)
+    }
+}
+
+/// sends an embed with buttons to flip between `page_count` pages, each built by `make_page`.
+/// this keeps listening for button presses for a few minutes, so it should be the last thing a command does
+async fn send_paginated(
+    ctx: &Context<'_>,
+    page_count: usize,
+    make_page: impl Fn(usize) -> CreateEmbed + Send + Sync,
+) -> Result<(), Error> {
+    let ctx_id = ctx.id();
+    let prev_button_id = format!("{ctx_id}prev");
+    let next_button_id = format!("{ctx_id}next");
+
+    let mut reply = CreateReply::default().embed(make_page(0));
+    if page_count > 1 {
+        reply = reply.components(vec![CreateActionRow::Buttons(vec![
+            CreateButton::new(&prev_button_id).emoji('◀'),
+            CreateButton::new(&next_button_id).emoji('▶'),
+        ])]);
+    }
+
+    send_application_reply(*ctx, reply).await?;
+
+    if page_count <= 1 {
+        return Ok(());
+    }
+
+    let mut current_page = 0;
+    while let Some(press) = ComponentInteractionCollector::new(ctx)
+        .filter(move |press| press.data.custom_id.starts_with(&ctx_id.to_string()))
+        .timeout(Duration::from_secs(300))
+        .await
+    {
+        if press.data.custom_id == next_button_id {
+            current_page = (current_page + 1) % page_count;
+        } else if press.data.custom_id == prev_button_id {
+            current_page = current_page.checked_sub(1).unwrap_or(page_count - 1);
+        } else {
+            continue;
+        }
+
+        press
+            .create_response(
+                ctx.serenity_context(),
+                CreateInteractionResponse::UpdateMessage(
+                    CreateInteractionResponseMessage::new().embed(make_page(current_page)),
+                ),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// whether the author is a DJ, and so can use the music admin commands. people who can manage the
+/// server always can, and until a DJ role is set up, so can anyone who can manage messages
+async fn is_dj(ctx: &Context<'_>) -> Result<bool, Error> {
+    let Some(member) = ctx.interaction.member.as_ref() else {
+        return Ok(false);
+    };
+    let guild_id = ctx.guild_id().expect("music commands are guild only");
+
+    Ok(member_is_dj(&ctx.data.database, guild_id, member).await?)
+}
+
+/// [`is_dj`], for a member that came from somewhere other than a command
+async fn member_is_dj(
+    database: &Database,
+    guild_id: GuildId,
+    member: &Member,
+) -> Result<bool, mongodb::error::Error> {
+    let permissions = member.permissions.unwrap_or_default();
+
+    if permissions.manage_guild() {
+        return Ok(true);
+    }
+
+    let settings = database.get_music_settings(&guild_id).await?;
+
+    Ok(settings.dj_role.map_or_else(
+        || permissions.manage_messages(),
+        |role| member.roles.contains(&role),
+    ))
+}
+
+/// adds `voter`'s vote to skip `track`, and skips it once at least half of the channel has voted.
+/// returns whether the track was skipped, along with the votes so far and how many are needed
+async fn vote_skip(
+    call: &Call,
+    track: &TrackHandle,
+    voter: UserId,
+    listeners: usize,
+) -> (bool, usize, usize) {
+    let mut typemap = track.typemap().write().await;
+
+    // a station never ends on its own, so whoever put it on can always turn it off
+    let needed = if typemap.contains_key::<LiveStream>()
+        && typemap
+            .get::<TrackRequester>()
+            .is_some_and(|r| r.id == voter)
+    {
+        1
+    } else {
+        (listeners / 2).max(1)
+    };
+
+    if !typemap.contains_key::<SkipVotes>() {
+        typemap.insert::<SkipVotes>(vec![]);
+    }
+    let votes = typemap
+        .get_mut::<SkipVotes>()
+        .expect("skip votes was just inserted");
+
+    if !votes.contains(&voter.get()) {
+        votes.push(voter.get());
+    }
+    let count = votes.len();
+    drop(typemap);
+
+    let skipped = count >= needed && call.queue().skip().is_ok();
+
+    (skipped, count, needed)
+}
+
+/// poise check for commands that only DJs can use. tells the author why if they can't
+pub async fn dj_check(ctx: poise::Context<'_, Data, Error>) -> Result<bool, Error> {
+    let poise::Context::Application(ctx) = ctx else {
+        return Ok(false);
+    };
+
+    if is_dj(&ctx).await? {
+        return Ok(true);
+    }
+
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    send_application_reply(
+        ctx,
+        CreateReply::default()
+            .content(local_get(
+                &ctx.data.translator,
+                "commands_music_notdj",
+                locale,
+            ))
+            .ephemeral(true),
+    )
+    .await?;
+
+    Ok(false)
+}
+
+/// converts a volume percentage into the multiplier songbird expects
+fn volume_to_gain(volume: u8) -> f32 {
+    f32::from(volume) / 100.
+}
+
+/// sets a track's volume in percent, keeping whatever gain normalization gave it
+async fn set_track_volume(track: &TrackHandle, volume: u8) {
+    let gain = track
+        .typemap()
+        .read()
+        .await
+        .get::<TrackGain>()
+        .copied()
+        .unwrap_or(1.);
+
+    let _ = track.set_volume(volume_to_gain(volume) * gain);
+}
+
+async fn get_loop_mode(sessions: &MusicSessions, guild_id: &GuildId) -> LoopMode {
+    sessions
+        .lock()
+        .await
+        .get(guild_id)
+        .map(|s| s.loop_mode)
+        .unwrap_or_default()
+}
+
+async fn get_filter(sessions: &MusicSessions, guild_id: &GuildId) -> FilterPreset {
+    sessions
+        .lock()
+        .await
+        .get(guild_id)
+        .map(|s| s.filter)
+        .unwrap_or_default()
+}
+
+/// gets the author's name and avatar as they appear in the guild
+async fn get_requester(ctx: &Context<'_>) -> TrackRequester {
+    let (name, avatar_url) = (ctx.author_member().await).map_or_else(
+        || (ctx.author().name.clone(), ctx.author().face()),
+        |member| (member.display_name().to_owned(), member.face()),
+    );
+
+    TrackRequester {
+        id: ctx.author().id,
+        name,
+        avatar_url,
+    }
+}
+
+/// what a track gets played from. library tracks are read straight off the disk and audio files
+/// are streamed as they are. only pages need yt-dlp to find the audio in them
+fn open_source(source: &Url, direct: bool) -> Box<dyn Compose> {
+    if let Some(path) = local_path(source) {
+        Box::new(File::new(path))
+    } else if direct || is_direct(source) {
+        Box::new(HttpRequest::new(HTTP_CLIENT.clone(), source.to_string()))
+    } else {
+        Box::new(YoutubeDl::new(HTTP_CLIENT.clone(), source.to_string()))
+    }
+}
+
+/// queues a track from a url at the given volume, and fills in its typemap with everything
+/// the rest of the music module expects
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_track(
+    call: &mut Call,
+    guild: GuildId,
+    sessions: &Arc<MusicSessions>,
+    track: PendingTrack,
+    requester: TrackRequester,
+    quick_leave: bool,
+    volume: f32,
+) -> TrackHandle {
+    let PendingTrack {
+        url: source,
+        metadata,
+        radio,
+        direct,
+    } = track;
+
+    let mut titles = None;
+    let inner: Box<dyn Compose> = if radio {
+        let (input, receiver) = RadioInput::new(source.clone());
+        titles = Some(receiver);
+        Box::new(input)
+    } else {
+        open_source(&source, direct)
+    };
+    let input = FilteredInput::new(inner, metadata.clone(), sessions.clone(), guild);
+    let handle = call.enqueue(input.into()).await;
+    let _ = handle.set_volume(volume);
+
+    let mut type_map = handle.typemap().write().await;
+    type_map.insert::<TrackSource>(source);
+    type_map.insert::<TrackMetadata>(metadata);
+    type_map.insert::<TrackRequester>(requester);
+    if quick_leave {
+        type_map.insert::<QuickLeave>(QuickLeave);
+    }
+    if let Some(titles) = titles {
+        type_map.insert::<LiveStream>(titles);
+    }
+    if direct {
+        type_map.insert::<DirectSource>(DirectSource);
+    }
+    drop(type_map);
+
+    handle
+}
+
 async fn get_handler(
     ctx: &Context<'_>,
     guild_id: &GuildId,
@@ -95,33 +612,15 @@ async fn get_handler(
     } else {
         let handler = manager.join(*guild_id, *connect_to).await?;
 
-        {
-            let mut lock = handler.lock().await;
-            lock.add_global_event(
-                songbird::Event::Periodic(Duration::from_secs(60), None),
-                AutoLeave {
-                    manager: manager.clone(),
-                    guild: *guild_id,
-                },
-            );
-
-            lock.add_global_event(
-                songbird::Event::Track(songbird::TrackEvent::End),
-                NowPlaying {
-                    http: ctx.serenity_context().http.clone(),
-                    manager: manager.clone(),
-                    guild: *guild_id,
-                },
-            );
-
-            lock.add_global_event(
-                songbird::Event::Track(songbird::TrackEvent::End),
-                QuickLeaveHandler {
-                    manager: manager.clone(),
-                    guild: *guild_id,
-                },
-            );
-        }
+        register_events(
+            &mut *handler.lock().await,
+            *guild_id,
+            &manager,
+            &ctx.serenity_context().http,
+            &ctx.serenity_context().cache,
+            &ctx.data.music_sessions,
+            &ctx.data.database,
+        );
 
         handler
     };
@@ -129,49 +628,287 @@ async fn get_handler(
     Ok(handler_lock)
 }
 
-async fn get_color_from_thumbnail(metadata: &AuxMetadata) -> Option<RGB<u8>> {
-    match metadata.thumbnail.clone() {
-        Some(t) => {
-            if let Ok(response) = reqwest::get(t).await {
-                response.bytes().await.map_or(None, |image_bytes|
-                    image::load_from_memory(&image_bytes).map_or(None, |image| {
-                        let pixels = image.to_rgb8();
-
-                        color_thief::get_palette(&pixels, color_thief::ColorFormat::Rgb, 10, 2).map_or(None, |mut pallette| {
-                            // sort by saturation
-                            pallette.sort_by(|a, b| saturation_from_rgb(a.r, a.g, a.b).partial_cmp(&saturation_from_rgb(b.r, b.g, b.b)).expect("NaN snuck in, something has gone wrong with pallette sorting"));
-                            pallette.reverse();
-                            Some(pallette[0])
-                        })
-                    }))
-            } else {
-                None
+/// adds the music module's event handlers to a newly created call
+fn register_events(
+    call: &mut Call,
+    guild: GuildId,
+    manager: &Arc<Songbird>,
+    http: &Arc<Http>,
+    cache: &Arc<Cache>,
+    sessions: &Arc<MusicSessions>,
+    database: &Arc<Database>,
+) {
+    call.add_global_event(
+        songbird::Event::Periodic(Duration::from_secs(60), None),
+        AutoLeave {
+            manager: manager.clone(),
+            cache: cache.clone(),
+            guild,
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::End),
+        LoopHandler {
+            manager: manager.clone(),
+            guild,
+            sessions: sessions.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::End),
+        NowPlaying {
+            http: http.clone(),
+            manager: manager.clone(),
+            guild,
+            sessions: sessions.clone(),
+            database: database.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Periodic(Duration::from_secs(15), None),
+        PanelProgress {
+            http: http.clone(),
+            manager: manager.clone(),
+            guild,
+            sessions: sessions.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::Playable),
+        Normalize {
+            manager: manager.clone(),
+            guild,
+            database: database.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Periodic(Duration::from_secs(10), None),
+        StreamTitles {
+            http: http.clone(),
+            manager: manager.clone(),
+            guild,
+            sessions: sessions.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Core(songbird::CoreEvent::DriverDisconnect),
+        PanelCleanup {
+            http: http.clone(),
+            guild,
+            sessions: sessions.clone(),
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::End),
+        QuickLeaveHandler {
+            manager: manager.clone(),
+            guild,
+        },
+    );
+
+    call.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::End),
+        PersistQueue {
+            manager: manager.clone(),
+            guild,
+            database: database.clone(),
+        },
+    );
+}
+
+/// saves a guild's queue so it can be picked back up after a restart.
+/// an empty queue (or a call that isn't connected) removes whatever was saved
+async fn persist_queue(
+    database: &Database,
+    guild_id: GuildId,
+    call: &Call,
+) -> Result<(), mongodb::error::Error> {
+    let tracks = call.queue().current_queue();
+
+    let Some(channel) = call.current_channel().filter(|_| !tracks.is_empty()) else {
+        database.delete_queue(&guild_id).await?;
+        return Ok(());
+    };
+
+    let position = match tracks[0].get_info().await {
+        Ok(state) => state.position,
+        Err(_) => Duration::ZERO,
+    };
+
+    let mut saved_tracks = Vec::with_capacity(tracks.len());
+    for track in &tracks {
+        let typemap = track.typemap().read().await;
+        if let (Some(pending), Some(requester)) = (
+            PendingTrack::from_typemap(&typemap),
+            typemap.get::<TrackRequester>(),
+        ) {
+            saved_tracks.push(SavedQueueTrack {
+                track: pending.to_saved(),
+                requester_id: requester.id,
+                requester_name: requester.name.clone(),
+                requester_avatar_url: requester.avatar_url.clone(),
+                quick_leave: typemap.contains_key::<QuickLeave>(),
+            });
+        }
+    }
+
+    database
+        .save_queue(&SavedQueue {
+            guild_id,
+            channel_id: ChannelId::new(channel.0.get()),
+            position_ms: u64::try_from(position.as_millis()).unwrap_or_default(),
+            tracks: saved_tracks,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// saves every active queue. this is meant to be called right before the bot shuts down,
+/// so it also stops track end events from touching the saved queues afterwards
+pub async fn persist_all_queues(manager: &Songbird, database: &Database) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+
+    let calls: Vec<_> = manager.iter().collect();
+    for (guild_id, call) in calls {
+        let guild_id = GuildId::new(guild_id.0.get());
+        if let Err(why) = persist_queue(database, guild_id, &*call.lock().await).await {
+            tracing::warn!("problem saving queue for {guild_id}: {:?}", why);
+        }
+    }
+}
+
+/// rejoins every voice channel that had a queue when the bot last shut down, and picks
+/// playback back up where it left off
+pub async fn restore_queues(
+    manager: Arc<Songbird>,
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    sessions: Arc<MusicSessions>,
+    database: Arc<Database>,
+) {
+    let queues = match database.get_queues().await {
+        Ok(queues) => queues,
+        Err(why) => {
+            tracing::error!("problem loading saved queues: {:?}", why);
+            return;
+        }
+    };
+
+    for saved in queues {
+        let handler_lock = match manager.join(saved.guild_id, saved.channel_id).await {
+            Ok(handler_lock) => handler_lock,
+            Err(why) => {
+                tracing::warn!("couldn't rejoin voice in {}: {:?}", saved.guild_id, why);
+                let _ = database.delete_queue(&saved.guild_id).await;
+                continue;
+            }
+        };
+
+        let volume = database
+            .get_music_settings(&saved.guild_id)
+            .await
+            .map_or(1., |s| volume_to_gain(s.volume));
+
+        let mut handler = handler_lock.lock().await;
+        register_events(
+            &mut handler,
+            saved.guild_id,
+            &manager,
+            &http,
+            &cache,
+            &sessions,
+            &database,
+        );
+
+        for (i, saved_track) in saved.tracks.into_iter().enumerate() {
+            let Some(track) = PendingTrack::from_saved(&saved_track.track) else {
+                continue;
+            };
+
+            let handle = enqueue_track(
+                &mut handler,
+                saved.guild_id,
+                &sessions,
+                track,
+                TrackRequester {
+                    id: saved_track.requester_id,
+                    name: saved_track.requester_name,
+                    avatar_url: saved_track.requester_avatar_url,
+                },
+                saved_track.quick_leave,
+                volume,
+            )
+            .await;
+
+            if i == 0 {
+                let _ = handle.seek(Duration::from_millis(saved.position_ms));
             }
         }
-        None => None,
+
+        tracing::info!(
+            "restored {} tracks in {}",
+            handler.queue().len(),
+            saved.guild_id
+        );
     }
 }
 
-// dervied from https://donatbalipapp.medium.com/colours-maths-90346fb5abda
-fn saturation_from_rgb(r: u8, g: u8, b: u8) -> f64 {
-    let max_rgb = f64::from(r.max(g).max(b));
-    let min_rgb = f64::from(r.min(g).min(b));
-    let luminosity = 0.5 * (max_rgb + min_rgb);
+/// formats a duration as `h:mm:ss`, or `m:ss` if it's shorter than an hour
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
 
-    if luminosity < 1. {
-        (max_rgb - min_rgb) / 1. - 2.0f64.mul_add(luminosity, -1.)
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
     } else {
-        0.
+        format!("{minutes}:{seconds:02}")
     }
 }
 
+/// draws how far through a track we are, like `▬▬▬🔘▬▬▬▬▬▬ 1:23 / 3:45`
+fn progress_bar(position: Duration, duration: Option<Duration>) -> String {
+    const WIDTH: usize = 16;
+
+    let Some(duration) = duration.filter(|d| !d.is_zero()) else {
+        return format!("`{}`", format_duration(position));
+    };
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let filled = ((position.as_secs_f64() / duration.as_secs_f64()) * WIDTH as f64)
+        .clamp(0., (WIDTH - 1) as f64) as usize;
+
+    format!(
+        "{}🔘{} `{} / {}`",
+        "▬".repeat(filled),
+        "▬".repeat(WIDTH - 1 - filled),
+        format_duration(position),
+        format_duration(duration)
+    )
+}
+
 fn make_now_playing_embed(
     metadata: &AuxMetadata,
     color: Option<RGB<u8>>,
     requester: Option<&TrackRequester>,
+    loop_mode: LoopMode,
+    filter: FilterPreset,
+    paused: bool,
+    position: Option<Duration>,
 ) -> CreateEmbed {
     let mut embed = CreateEmbed::new()
-        .title("Now Playing:")
+        .title(if paused { "Paused:" } else { "Now Playing:" })
         .thumbnail(
             metadata
                 .thumbnail
@@ -182,8 +919,8 @@ fn make_now_playing_embed(
             "*{}*",
             metadata
                 .source_url
-                .clone()
-                .unwrap_or_else(|| "https://http.cat/404".to_string())
+                .as_deref()
+                .map_or_else(|| "https://http.cat/404".to_string(), display_url)
         ))
         .field(
             "Title",
@@ -197,6 +934,18 @@ fn make_now_playing_embed(
         )
         .timestamp(Utc::now());
 
+    if loop_mode != LoopMode::Off {
+        embed = embed.field("Loop", loop_mode.name(), true);
+    }
+
+    if filter != FilterPreset::Off {
+        embed = embed.field("Filter", filter.name(), true);
+    }
+
+    if let Some(position) = position {
+        embed = embed.field("Progress", progress_bar(position, metadata.duration), false);
+    }
+
     if let Some(color) = color {
         embed = embed.color((color.r, color.g, color.b));
     }
@@ -215,35 +964,140 @@ struct NowPlaying {
     http: Arc<Http>,
     manager: Arc<Songbird>,
     guild: GuildId,
+    sessions: Arc<MusicSessions>,
+    database: Arc<Database>,
+}
+
+impl NowPlaying {
+    /// adds the track that just ended to the guild's play history
+    async fn record_history(&self, state: &TrackState, track: &TrackHandle) {
+        // tracks that never got to play (e.g. everything cleared by a stop) aren't history
+        if state.play_time.is_zero() || SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let typemap = track.typemap().read().await;
+        if typemap.contains_key::<Restarted>() {
+            return;
+        }
+        let Some(pending) = PendingTrack::from_typemap(&typemap) else {
+            return;
+        };
+        let requester = typemap.get::<TrackRequester>();
+
+        let entry = HistoryEntry {
+            guild_id: self.guild,
+            track: pending.to_saved(),
+            requester_id: requester.map(|r| r.id),
+            requester_name: requester.map(|r| r.name.clone()),
+            played_at: mongodb::bson::DateTime::now(),
+        };
+        drop(typemap);
+
+        if let Err(why) = self.database.add_history(&entry).await {
+            tracing::warn!("problem saving play history: {:?}", why);
+        }
+
+        // the track that just finished is what `/music previous` goes back to now
+        if let Some(session) = self.sessions.lock().await.get_mut(&self.guild) {
+            session.history_cursor = 0;
+        }
+    }
 }
 
 #[async_trait]
 impl EventHandler for NowPlaying {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
         // nested if let hell
-        if let EventContext::Track(_track_list) = ctx {
+        if let EventContext::Track(track_list) = ctx {
+            if let Some((state, ended)) = track_list.first() {
+                self.record_history(state, ended).await;
+            }
+
             let handler_lock = self.manager.get(self.guild)?;
             let handler = handler_lock.lock().await;
-            let np = handler.queue().current()?;
             let channel_id = handler.current_channel()?;
+            let Some(np) = handler.queue().current() else {
+                drop(handler);
+                if let Some(session) = self.sessions.lock().await.get_mut(&self.guild) {
+                    session.arranged = false;
+                }
+                // the queue ran out, so the panel has nothing left to control
+                refresh_panel(&self.http, &self.manager, &self.sessions, self.guild).await;
+                return None;
+            };
             drop(handler);
-            if let Channel::Guild(channel) =
-                self.http.get_channel(channel_id.0.into()).await.ok()?
+
+            let settings = self
+                .database
+                .get_music_settings(&self.guild)
+                .await
+                .unwrap_or_else(|_| MusicSettings::new(self.guild));
+            let announce_to = settings.announcement_channel(ChannelId::new(channel_id.0.get()))?;
+
+            if let Err(why) = show_panel(
+                &self.http,
+                announce_to,
+                &np,
+                &self.sessions,
+                self.guild,
+                &settings,
+            )
+            .await
             {
-                let typemap = np.typemap().read().await;
-                let metadata = typemap
-                    .get::<TrackMetadata>()
-                    .expect("tracks should ALWAYS have metadata");
-                let color = get_color_from_thumbnail(metadata).await;
-                let requester = typemap.get::<TrackRequester>();
-                let embed = make_now_playing_embed(metadata, color, requester);
-                drop(typemap);
-
-                if let Err(why) = channel
-                    .send_message(&self.http, CreateMessage::new().add_embed(embed))
-                    .await
-                {
-                    tracing::warn!("Error sending now playing message: {:?}", why);
+                tracing::warn!("Error sending now playing message: {:?}", why);
+            }
+        }
+
+        None
+    }
+}
+
+struct LoopHandler {
+    manager: Arc<Songbird>,
+    guild: GuildId,
+    sessions: Arc<MusicSessions>,
+}
+
+#[async_trait]
+impl EventHandler for LoopHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(list) = ctx {
+            let handler_lock = self.manager.get(self.guild)?;
+
+            match get_loop_mode(&self.sessions, &self.guild).await {
+                LoopMode::Off => {}
+                LoopMode::Track => {
+                    // looping is set per track, so carry it over to whatever plays next
+                    if let Some(current) = handler_lock.lock().await.queue().current() {
+                        let _ = current.enable_loop();
+                    }
+                }
+                LoopMode::Queue => {
+                    let (state, ended) = list.first()?;
+                    let volume = state.volume;
+
+                    // skipped, removed and stopped tracks drop out of the loop
+                    if !matches!(state.playing, PlayMode::End) {
+                        return None;
+                    }
+
+                    let typemap = ended.typemap().read().await;
+                    let track = PendingTrack::from_typemap(&typemap)?;
+                    let requester = typemap.get::<TrackRequester>()?.clone();
+                    let quick_leave = typemap.contains_key::<QuickLeave>();
+                    drop(typemap);
+
+                    enqueue_track(
+                        &mut *handler_lock.lock().await,
+                        self.guild,
+                        &self.sessions,
+                        track,
+                        requester,
+                        quick_leave,
+                        volume,
+                    )
+                    .await;
                 }
             }
         }
@@ -252,14 +1106,40 @@ impl EventHandler for NowPlaying {
     }
 }
 
+struct PersistQueue {
+    manager: Arc<Songbird>,
+    guild: GuildId,
+    database: Arc<Database>,
+}
+
+#[async_trait]
+impl EventHandler for PersistQueue {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        // tracks get torn down on shutdown, which would otherwise wipe the queues we just saved
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let handler_lock = self.manager.get(self.guild)?;
+        let handler = handler_lock.lock().await;
+        if let Err(why) = persist_queue(&self.database, self.guild, &handler).await {
+            tracing::warn!("problem saving queue: {:?}", why);
+        }
+
+        None
+    }
+}
+
 struct AutoLeave {
     manager: Arc<Songbird>,
+    cache: Arc<Cache>,
     guild: GuildId,
 }
 
 #[async_trait]
 impl EventHandler for AutoLeave {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        // songbird hands periodic global events an empty track list, so this runs for both
         if let EventContext::Track(list) = ctx {
             if let Some(handler_lock) = self.manager.get(self.guild) {
                 let mut handler = handler_lock.lock().await;
@@ -268,16 +1148,55 @@ impl EventHandler for AutoLeave {
                         && handler.queue().is_empty()
                     {
                         let _dc = handler.leave().await;
-                        drop(handler);
+                        return None;
                     }
                 }
+                drop(handler);
             }
+
+            self.leave_empty_stream().await;
         }
 
         None
     }
 }
 
+impl AutoLeave {
+    /// a station would keep playing to an empty channel forever, so once everyone's left, so
+    /// does the bot
+    async fn leave_empty_stream(&self) {
+        let Some(handler_lock) = self.manager.get(self.guild) else {
+            return;
+        };
+        let mut handler = handler_lock.lock().await;
+
+        let Some(channel) = handler.current_channel() else {
+            return;
+        };
+        let Some(current) = handler.queue().current() else {
+            return;
+        };
+        if !current.typemap().read().await.contains_key::<LiveStream>() {
+            return;
+        }
+
+        let us = self.cache.current_user().id;
+        let listeners = self.cache.guild(self.guild).map_or(0, |guild| {
+            guild
+                .voice_states
+                .values()
+                .filter(|v| v.channel_id.is_some_and(|c| c.get() == channel.0.get()))
+                .filter(|v| v.user_id != us && !v.member.as_ref().is_some_and(|m| m.user.bot))
+                .count()
+        });
+
+        if listeners == 0 {
+            handler.queue().stop();
+            let _dc = handler.leave().await;
+        }
+    }
+}
+
 struct QuickLeaveHandler {
     manager: Arc<Songbird>,
     guild: GuildId,
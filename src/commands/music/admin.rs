@@ -1,18 +1,42 @@
-use poise::{send_application_reply, CreateReply};
+use std::time::Duration;
 
-use crate::{commands::music::get_client, local_get, Context, Error};
+use poise::{
+    send_application_reply,
+    serenity_prelude::{CreateEmbed, GuildChannel, Mentionable, Role},
+    ChoiceParameter, CreateReply,
+};
+
+use crate::{
+    commands::music::{
+        dj_check, format_duration, get_client, loudness::normalize, queue::fair_order,
+        set_track_volume,
+    },
+    data::Announcements,
+    local_get, Context, Error,
+};
 
 #[poise::command(
     slash_command,
-    subcommands("force_skip", "stop"),
-    required_permissions = "MANAGE_MESSAGES"
+    subcommands(
+        "force_skip",
+        "stop",
+        "max_volume",
+        "playlist_limit",
+        "limits",
+        "progress_bar",
+        "normalization",
+        "fair_queue",
+        "announcements",
+        "default_color",
+        "dj_role"
+    )
 )]
 #[allow(clippy::unused_async)]
 pub async fn admin(_: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-#[poise::command(slash_command, ephemeral, guild_only)]
+#[poise::command(slash_command, ephemeral, guild_only, check = "dj_check")]
 async fn force_skip(ctx: Context<'_>) -> Result<(), Error> {
     let locale = ctx
         .locale()
@@ -79,7 +103,7 @@ async fn force_skip(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-#[poise::command(slash_command, ephemeral, guild_only)]
+#[poise::command(slash_command, ephemeral, guild_only, check = "dj_check")]
 async fn stop(ctx: Context<'_>, leave: Option<bool>) -> Result<(), Error> {
     let locale = ctx
         .locale()
@@ -148,3 +172,498 @@ async fn stop(ctx: Context<'_>, leave: Option<bool>) -> Result<(), Error> {
 
     Ok(())
 }
+
+/// sets the highest volume, in percent, that anyone can pick with /music volume
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn max_volume(
+    ctx: Context<'_>,
+    #[min = 0]
+    #[max = 200]
+    max_volume: u8,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.max_volume = max_volume;
+
+    if settings.volume > max_volume {
+        settings.volume = max_volume;
+
+        if let Some(handler_lock) = get_client(&ctx).await.get(guild_id) {
+            for track in handler_lock.lock().await.queue().current_queue() {
+                set_track_volume(&track, max_volume).await;
+            }
+        }
+    }
+
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(
+                &ctx.data.translator,
+                "commands_music_admin_maxvolume_success",
+                locale,
+            )
+            .replace("{volume}", &max_volume.to_string()),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// sets how many tracks a single playlist link can add to the queue
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn playlist_limit(
+    ctx: Context<'_>,
+    #[min = 1]
+    #[max = 500]
+    limit: u16,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.max_playlist_tracks = limit;
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(
+                &ctx.data.translator,
+                "commands_music_admin_playlistlimit_success",
+                locale,
+            )
+            .replace("{count}", &limit.to_string()),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// changes what can be queued. 0 removes a limit, and leaving everything empty shows the current limits
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn limits(
+    ctx: Context<'_>,
+    queue_length: Option<u16>,
+    track_minutes: Option<u32>,
+    per_user: Option<u16>,
+    livestreams: Option<bool>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+
+    if let Some(queue_length) = queue_length {
+        settings.max_queue_length = Some(queue_length).filter(|l| *l > 0);
+    }
+    if let Some(track_minutes) = track_minutes {
+        settings.max_track_duration = Some(track_minutes.saturating_mul(60)).filter(|d| *d > 0);
+    }
+    if let Some(per_user) = per_user {
+        settings.max_tracks_per_user = Some(per_user).filter(|l| *l > 0);
+    }
+    if let Some(livestreams) = livestreams {
+        settings.allow_livestreams = livestreams;
+    }
+
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    let or_none = |limit: Option<String>| limit.unwrap_or_else(|| "none".to_string());
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().embed(
+            CreateEmbed::new()
+                .title("Music limits:")
+                .field(
+                    "Queue length",
+                    or_none(settings.max_queue_length.map(|l| l.to_string())),
+                    true,
+                )
+                .field(
+                    "Track length",
+                    or_none(
+                        settings
+                            .max_track_duration
+                            .map(|d| format_duration(Duration::from_secs(d.into()))),
+                    ),
+                    true,
+                )
+                .field(
+                    "Tracks per person",
+                    or_none(settings.max_tracks_per_user.map(|l| l.to_string())),
+                    true,
+                )
+                .field(
+                    "Livestreams",
+                    if settings.allow_livestreams {
+                        "allowed"
+                    } else {
+                        "not allowed"
+                    },
+                    true,
+                ),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// turns the live progress bar on the now playing message on or off
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn progress_bar(ctx: Context<'_>, enabled: bool) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.now_playing_progress = enabled;
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    // the current panel picks it up right away, rather than on the next track
+    if let Some(panel) = ctx
+        .data
+        .music_sessions
+        .lock()
+        .await
+        .get_mut(&guild_id)
+        .and_then(|s| s.panel.as_mut())
+    {
+        panel.progress = enabled;
+    }
+
+    let key = if enabled {
+        "commands_music_admin_progressbar_on"
+    } else {
+        "commands_music_admin_progressbar_off"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// turns loudness normalization on or off, and sets how loud tracks should end up
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn normalization(
+    ctx: Context<'_>,
+    enabled: bool,
+    #[min = -30]
+    #[max = -5]
+    target: Option<i8>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.normalize = enabled;
+    if let Some(target) = target {
+        settings.target_loudness = target;
+    }
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    // the current track picks it up right away if it has been measured, everything else when it
+    // starts
+    let manager = get_client(&ctx).await;
+    let current = match manager.get(guild_id) {
+        Some(handler_lock) => handler_lock.lock().await.queue().current(),
+        None => None,
+    };
+    if let Some(current) = current {
+        normalize(guild_id, &ctx.data.database, &current).await;
+    }
+
+    let key = if enabled {
+        "commands_music_admin_normalization_on"
+    } else {
+        "commands_music_admin_normalization_off"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(&ctx.data.translator, key, locale)
+                .replace("{target}", &settings.target_loudness.to_string()),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// makes everyone with tracks in the queue take turns, instead of playing them in the order they
+/// were queued
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn fair_queue(ctx: Context<'_>, enabled: bool) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.fair_queue = enabled;
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    // turning it off leaves the queue as it is, there's no going back to the order it was in.
+    // turning it on takes over from any order the queue was put in by hand
+    if enabled {
+        ctx.data
+            .music_sessions
+            .lock()
+            .await
+            .entry(guild_id)
+            .or_default()
+            .arranged = false;
+
+        if let Some(handler_lock) = get_client(&ctx).await.get(guild_id) {
+            fair_order(&*handler_lock.lock().await).await;
+        }
+    }
+
+    let key = if enabled {
+        "commands_music_admin_fairqueue_on"
+    } else {
+        "commands_music_admin_fairqueue_off"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(ChoiceParameter)]
+enum AnnouncementMode {
+    #[name = "voice channel chat"]
+    VoiceChat,
+    #[name = "text channel"]
+    Channel,
+    #[name = "off"]
+    Off,
+}
+
+/// picks where now playing messages get posted
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn announcements(
+    ctx: Context<'_>,
+    mode: AnnouncementMode,
+    #[channel_types("Text")] channel: Option<GuildChannel>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let announcements = match (mode, channel) {
+        (AnnouncementMode::VoiceChat, _) => Announcements::VoiceChat,
+        (AnnouncementMode::Channel, Some(channel)) => Announcements::Channel(channel.id),
+        (AnnouncementMode::Channel, None) => {
+            send_application_reply(
+                ctx,
+                CreateReply::default().content(local_get(
+                    &ctx.data.translator,
+                    "commands_music_admin_announcements_nochannel",
+                    locale,
+                )),
+            )
+            .await?;
+
+            return Ok(());
+        }
+        (AnnouncementMode::Off, _) => Announcements::Off,
+    };
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.announcements = announcements;
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    let content = match announcements {
+        Announcements::VoiceChat => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_announcements_voicechat",
+            locale,
+        ),
+        Announcements::Channel(channel) => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_announcements_channel",
+            locale,
+        )
+        .replace("{channel}", &channel.mention().to_string()),
+        Announcements::Off => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_announcements_off",
+            locale,
+        ),
+    };
+
+    send_application_reply(ctx, CreateReply::default().content(content)).await?;
+
+    Ok(())
+}
+
+/// sets the embed color used when a track's thumbnail doesn't have a good one, like #5865f2.
+/// leave it empty to go back to no color
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn default_color(ctx: Context<'_>, color: Option<String>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    let parsed = color
+        .as_deref()
+        .map(|c| c.trim().trim_start_matches('#'))
+        .filter(|hex| hex.len() == 6)
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+
+    if color.is_some() && parsed.is_none() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_admin_defaultcolor_invalid",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.default_color = parsed;
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    let content = match parsed {
+        Some(parsed) => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_defaultcolor_success",
+            locale,
+        )
+        .replace("{color}", &format!("#{parsed:06x}")),
+        None => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_defaultcolor_cleared",
+            locale,
+        ),
+    };
+
+    send_application_reply(ctx, CreateReply::default().content(content)).await?;
+
+    Ok(())
+}
+
+/// sets the role that can use these commands. leave it empty to go back to anyone who can manage messages
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+async fn dj_role(ctx: Context<'_>, role: Option<Role>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for guild only command");
+
+    ctx.defer_ephemeral().await?;
+
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.dj_role = role.as_ref().map(|r| r.id);
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    let content = match role {
+        Some(role) => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_djrole_success",
+            locale,
+        )
+        .replace("{role}", &role.mention().to_string()),
+        None => local_get(
+            &ctx.data.translator,
+            "commands_music_admin_djrole_cleared",
+            locale,
+        ),
+    };
+
+    send_application_reply(ctx, CreateReply::default().content(content)).await?;
+
+    Ok(())
+}
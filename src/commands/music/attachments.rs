@@ -0,0 +1,369 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Cursor, ErrorKind},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use lru::LruCache;
+use poise::serenity_prelude::CreateAttachment;
+use songbird::input::{
+    codecs::{get_codec_registry, get_probe},
+    AuxMetadata, Compose, HttpRequest,
+};
+use symphonia::core::{
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey, StandardVisualKey},
+    probe::Hint,
+};
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::{local_get, locale::Translator};
+
+use super::HTTP_CLIENT;
+
+/// how many packets we read looking for one that decodes before deciding a file is broken. video
+/// files have plenty of packets that aren't audio, so this is generous
+const MAX_CHECKED_PACKETS: usize = 256;
+/// uploads get read into memory to be checked, so anything bigger than this is turned away first
+pub(super) const MAX_ATTACHMENT_MB: u32 = 50;
+
+/// why an uploaded file can't be played
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Unplayable {
+    /// not a format we know, or too broken to tell
+    NotMedia,
+    /// a video (or something else) with no audio in it
+    NoAudio,
+    UnsupportedCodec,
+    /// looks fine from the outside, but the audio doesn't decode
+    Corrupt,
+    /// bigger than [`MAX_ATTACHMENT_MB`], so it wasn't downloaded
+    TooLarge,
+}
+
+impl Unplayable {
+    /// explains the problem in a way that can be sent back to whoever uploaded the file
+    pub(super) fn explain(self, translator: &Translator, locale: &str) -> String {
+        let key = match self {
+            Self::NotMedia => "commands_music_playback_attachment_notaudio",
+            Self::NoAudio => "commands_music_playback_attachment_noaudio",
+            Self::UnsupportedCodec => "commands_music_playback_attachment_unsupportedcodec",
+            Self::Corrupt => "commands_music_playback_attachment_corrupt",
+            Self::TooLarge => "commands_music_playback_attachment_toolarge",
+        };
+
+        local_get(translator, key, locale).replace("{size}", &MAX_ATTACHMENT_MB.to_string())
+    }
+}
+
+/// cover art that came embedded in an uploaded file
+pub(super) struct Cover {
+    pub data: Vec<u8>,
+    /// what the cover is called when it gets attached to a message
+    pub filename: String,
+}
+
+impl Cover {
+    pub fn attachment(&self) -> CreateAttachment {
+        CreateAttachment::bytes(self.data.clone(), &self.filename)
+    }
+
+    /// lets an embed show the cover, as long as it's attached to the same message
+    pub fn attachment_url(&self) -> String {
+        format!("attachment://{}", self.filename)
+    }
+}
+
+/// embedded covers, by the url of the file they came from. they don't have a url of their own,
+/// so they're kept here until the now playing message needs them
+static COVERS: LazyLock<Mutex<LruCache<String, Arc<Cover>>>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(64).expect("cache size isn't zero"),
+    ))
+});
+
+/// the embedded cover of a track that came from an uploaded file, if it had one
+pub(super) async fn get_cover(metadata: &AuxMetadata) -> Option<Arc<Cover>> {
+    let url = metadata.source_url.as_ref()?;
+
+    COVERS.lock().await.get(url).cloned()
+}
+
+/// makes sure an uploaded file has audio we can actually play, and reads its tags and cover art
+pub(super) async fn read_attachment(
+    url: &Url,
+    filename: &str,
+    content_type: Option<&str>,
+    bytes: Vec<u8>,
+) -> Result<AuxMetadata, Unplayable> {
+    let name = filename.to_string();
+    let content_type = content_type.map(ToString::to_string);
+    // probing and decoding have to read through the file, which is too slow to do on the runtime
+    let probed = tokio::task::spawn_blocking(move || {
+        probe(Box::new(Cursor::new(bytes)), &name, content_type.as_deref())
+    })
+    .await
+    .map_err(|_| Unplayable::NotMedia)??;
+
+    Ok(remember(url, probed).await)
+}
+
+/// the same as [`read_attachment`], for a file from the local library
+pub(super) async fn read_local(url: &Url, path: PathBuf) -> Result<AuxMetadata, Unplayable> {
+    let probed = tokio::task::spawn_blocking(move || probe_file(&path))
+        .await
+        .map_err(|_| Unplayable::NotMedia)??;
+
+    Ok(remember(url, probed).await)
+}
+
+/// the same as [`read_attachment`], for an audio file somewhere on the web. only as much of it as
+/// probing needs gets downloaded
+pub(super) async fn read_remote(
+    url: &Url,
+    content_type: Option<&str>,
+) -> Result<AuxMetadata, Unplayable> {
+    let stream = HttpRequest::new(HTTP_CLIENT.clone(), url.to_string())
+        .create_async()
+        .await
+        .map_err(|why| {
+            tracing::debug!("couldn't open {}: {:?}", url, why);
+            Unplayable::NotMedia
+        })?;
+    let filename = url
+        .path_segments()
+        .and_then(Iterator::last)
+        .unwrap_or_default()
+        .to_string();
+    let content_type = content_type.map(ToString::to_string);
+
+    let probed = tokio::task::spawn_blocking(move || {
+        probe(stream.input, &filename, content_type.as_deref())
+    })
+    .await
+    .map_err(|_| Unplayable::NotMedia)??;
+
+    Ok(remember(url, probed).await)
+}
+
+/// hangs on to the cover for the now playing message, and fills in where the track came from
+async fn remember(url: &Url, (mut metadata, cover): (AuxMetadata, Option<Cover>)) -> AuxMetadata {
+    metadata.source_url = Some(url.to_string());
+
+    if let Some(cover) = cover {
+        COVERS.lock().await.put(url.to_string(), Arc::new(cover));
+    }
+
+    metadata
+}
+
+/// [`probe`]s a file on disk. this blocks, so it shouldn't be called on the runtime
+pub(super) fn probe_file(path: &Path) -> Result<(AuxMetadata, Option<Cover>), Unplayable> {
+    let file = File::open(path).map_err(|why| {
+        tracing::debug!("couldn't open {}: {:?}", path.display(), why);
+        Unplayable::NotMedia
+    })?;
+    let filename = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+
+    probe(Box::new(file), filename, None)
+}
+
+/// checks that `source` has audio that decodes, and reads its tags and cover art. whatever the
+/// tags don't say about the title and artist gets worked out from `filename`
+fn probe(
+    source: Box<dyn MediaSource>,
+    filename: &str,
+    content_type: Option<&str>,
+) -> Result<(AuxMetadata, Option<Cover>), Unplayable> {
+    // content types (especially discord's) are only a guess, so it's just a hint here
+    let mut hint = Hint::new();
+    if let Some(extension) = Path::new(filename).extension().and_then(OsStr::to_str) {
+        hint.with_extension(extension);
+    }
+    if let Some(content_type) = content_type {
+        hint.mime_type(content_type);
+    }
+
+    let stream = MediaSourceStream::new(source, MediaSourceStreamOptions::default());
+
+    let mut probed = match get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(why) => {
+            tracing::debug!("couldn't probe {}: {:?}", filename, why);
+            return Err(Unplayable::NotMedia);
+        }
+    };
+
+    // video tracks (and anything else symphonia doesn't know) come out with a null codec and no
+    // audio parameters, so anything else is audio, even if we can't decode it
+    let audio_tracks: Vec<_> = probed
+        .format
+        .tracks()
+        .iter()
+        .filter(|t| {
+            t.codec_params.codec != CODEC_TYPE_NULL
+                || t.codec_params.sample_rate.is_some()
+                || t.codec_params.channels.is_some()
+        })
+        .collect();
+
+    if audio_tracks.is_empty() {
+        return Err(Unplayable::NoAudio);
+    }
+
+    let Some(track) = audio_tracks.into_iter().find(|t| {
+        get_codec_registry()
+            .get_codec(t.codec_params.codec)
+            .is_some()
+    }) else {
+        return Err(Unplayable::UnsupportedCodec);
+    };
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let mut decoder = get_codec_registry()
+        .make(&params, &DecoderOptions::default())
+        .map_err(|_| Unplayable::UnsupportedCodec)?;
+
+    let mut metadata = AuxMetadata {
+        channels: params
+            .channels
+            .and_then(|channels| u8::try_from(channels.count()).ok()),
+        sample_rate: params.sample_rate,
+        duration: params.time_base.zip(params.n_frames).map(|(base, frames)| {
+            let time = base.calc_time(frames);
+            Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+        }),
+        ..AuxMetadata::default()
+    };
+
+    // tags can come before the container (like id3v2 does) or inside it, and a file can have both
+    let before = probed.metadata.get().and_then(|log| log.current().cloned());
+    let inside = probed.format.metadata().current().cloned();
+
+    let mut cover = None;
+    for revision in before.iter().chain(inside.iter()) {
+        read_revision(revision, &mut metadata, &mut cover);
+    }
+
+    if metadata.title.is_none() {
+        let (artist, title) = names_from_filename(filename);
+        metadata.title = Some(title);
+        if metadata.artist.is_none() {
+            metadata.artist = artist;
+        }
+    }
+
+    check_decodes(probed.format.as_mut(), decoder.as_mut(), track_id)?;
+
+    Ok((metadata, cover))
+}
+
+/// decodes the start of a track, so files that only look like they're fine get caught before
+/// they make it into the queue
+fn check_decodes(
+    format: &mut dyn FormatReader,
+    decoder: &mut dyn Decoder,
+    track_id: u32,
+) -> Result<(), Unplayable> {
+    for _ in 0..MAX_CHECKED_PACKETS {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // running out of file before any audio decoded counts too
+            Err(SymphoniaError::IoError(why)) if why.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Unplayable::Corrupt)
+            }
+            Err(why) => {
+                tracing::debug!("problem reading attachment: {:?}", why);
+                return Err(Unplayable::Corrupt);
+            }
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(_) => return Ok(()),
+            // a bad packet here and there is fine, songbird skips those too
+            Err(SymphoniaError::DecodeError(_)) => {}
+            Err(why) => {
+                tracing::debug!("problem decoding attachment: {:?}", why);
+                return Err(Unplayable::Corrupt);
+            }
+        }
+    }
+
+    Err(Unplayable::Corrupt)
+}
+
+/// fills in whatever `metadata` and `cover` don't have yet from one set of tags
+fn read_revision(
+    revision: &MetadataRevision,
+    metadata: &mut AuxMetadata,
+    cover: &mut Option<Cover>,
+) {
+    for tag in revision.tags() {
+        let field = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => &mut metadata.title,
+            Some(StandardTagKey::Artist) => &mut metadata.artist,
+            Some(StandardTagKey::Album) => &mut metadata.album,
+            Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) => &mut metadata.date,
+            _ => continue,
+        };
+
+        let value = tag.value.to_string();
+        if field.is_none() && !value.trim().is_empty() {
+            *field = Some(value.trim().to_string());
+        }
+    }
+
+    if cover.is_none() {
+        let visuals = revision.visuals();
+        *cover = visuals
+            .iter()
+            .find(|v| v.usage == Some(StandardVisualKey::FrontCover))
+            .or_else(|| visuals.first())
+            .map(|visual| Cover {
+                data: visual.data.to_vec(),
+                filename: format!(
+                    "cover.{}",
+                    match visual.media_type.as_str() {
+                        "image/jpeg" | "image/jpg" => "jpg",
+                        "image/gif" => "gif",
+                        "image/webp" => "webp",
+                        _ => "png",
+                    }
+                ),
+            });
+    }
+}
+
+/// guesses an artist and title from a file called something like `Artist - Title.mp3`
+fn names_from_filename(filename: &str) -> (Option<String>, String) {
+    let stem = Path::new(filename)
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .unwrap_or(filename)
+        .replace('_', " ");
+
+    match stem.split_once(" - ") {
+        Some((artist, title)) if !artist.trim().is_empty() && !title.trim().is_empty() => {
+            (Some(artist.trim().to_string()), title.trim().to_string())
+        }
+        _ => (None, stem.trim().to_string()),
+    }
+}
@@ -0,0 +1,328 @@
+use std::{num::NonZeroUsize, sync::LazyLock, time::Duration};
+
+use image::DynamicImage;
+use lru::LruCache;
+use rgb::RGB;
+use songbird::input::AuxMetadata;
+use tokio::sync::Mutex;
+
+use super::{attachments::get_cover, HTTP_CLIENT};
+
+/// how long a thumbnail gets to download before we give up on coloring the embed
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// thumbnails bigger than this (in either direction) get shrunk before looking for colors in them
+const MAX_IMAGE_SIZE: u32 = 256;
+
+/// colors we've already worked out, by thumbnail url
+static COLOR_CACHE: LazyLock<Mutex<LruCache<String, Option<RGB<u8>>>>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(512).expect("cache size isn't zero"),
+    ))
+});
+
+/// picks an accent color for a track's embed out of its thumbnail, or its embedded cover if it
+/// came from an uploaded file
+pub(super) async fn get_color_from_thumbnail(metadata: &AuxMetadata) -> Option<RGB<u8>> {
+    let Some(url) = metadata.thumbnail.clone() else {
+        return get_color_from_cover(metadata).await;
+    };
+
+    if let Some(color) = COLOR_CACHE.lock().await.get(&url) {
+        return *color;
+    }
+
+    // failed downloads aren't cached, they might work next time
+    let response = HTTP_CLIENT
+        .get(&url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .ok()?;
+    let bytes = response.bytes().await.ok()?;
+
+    // decoding and palette extraction are slow enough to hold up the runtime
+    let color = tokio::task::spawn_blocking(move || color_from_image(&bytes))
+        .await
+        .ok()?;
+
+    COLOR_CACHE.lock().await.put(url, color);
+
+    color
+}
+
+async fn get_color_from_cover(metadata: &AuxMetadata) -> Option<RGB<u8>> {
+    let url = metadata.source_url.clone()?;
+
+    if let Some(color) = COLOR_CACHE.lock().await.get(&url) {
+        return *color;
+    }
+
+    let cover = get_cover(metadata).await?;
+    let color = tokio::task::spawn_blocking(move || color_from_image(&cover.data))
+        .await
+        .ok()?;
+
+    COLOR_CACHE.lock().await.put(url, color);
+
+    color
+}
+
+/// colors darker than this (hsv value) look black in an embed
+const MIN_VALUE: f64 = 0.2;
+/// colors lighter than this (hsl lightness) look white in an embed
+const MAX_LIGHTNESS: f64 = 0.85;
+/// colors less saturated than this look grey
+const MIN_SATURATION: f64 = 0.2;
+
+/// the saturation and lightness parts of a color in hsl, both from 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct Hsl {
+    pub saturation: f64,
+    pub lightness: f64,
+}
+
+/// the saturation and value parts of a color in hsv, both from 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct Hsv {
+    pub saturation: f64,
+    pub value: f64,
+}
+
+/// the biggest and smallest channel of a color, scaled to 0 to 1
+fn max_min(color: RGB<u8>) -> (f64, f64) {
+    let max = color.r.max(color.g).max(color.b);
+    let min = color.r.min(color.g).min(color.b);
+
+    (f64::from(max) / 255., f64::from(min) / 255.)
+}
+
+impl From<RGB<u8>> for Hsl {
+    fn from(color: RGB<u8>) -> Self {
+        let (max, min) = max_min(color);
+        let lightness = (max + min) / 2.;
+        // greys (including black and white) have no saturation, and would divide by zero
+        let saturation = if color.r == color.g && color.g == color.b {
+            0.
+        } else {
+            (max - min) / (1. - 2f64.mul_add(lightness, -1.).abs())
+        };
+
+        Self {
+            saturation,
+            lightness,
+        }
+    }
+}
+
+impl From<RGB<u8>> for Hsv {
+    fn from(color: RGB<u8>) -> Self {
+        let (max, min) = max_min(color);
+        let saturation = if color.r == 0 && color.g == 0 && color.b == 0 {
+            0.
+        } else {
+            (max - min) / max
+        };
+
+        Self {
+            saturation,
+            value: max,
+        }
+    }
+}
+
+/// how good a color is as an embed accent. vivid colors that aren't too dark or too light score
+/// highest, and anything close to black, white or grey doesn't score at all
+fn accent_score(color: RGB<u8>) -> Option<f64> {
+    let hsl = Hsl::from(color);
+    let hsv = Hsv::from(color);
+
+    if hsv.value < MIN_VALUE || hsl.lightness > MAX_LIGHTNESS || hsl.saturation < MIN_SATURATION {
+        return None;
+    }
+
+    // lightness right in the middle is where colors are the most colorful
+    Some(hsv.saturation * (1. - (hsl.lightness - 0.5).abs()))
+}
+
+/// picks the best accent color out of a palette, if any of it is usable
+fn pick_accent(palette: &[RGB<u8>]) -> Option<RGB<u8>> {
+    palette
+        .iter()
+        .filter_map(|color| accent_score(*color).map(|score| (*color, score)))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(color, _)| color)
+}
+
+/// shrinks `image` to fit within [`MAX_IMAGE_SIZE`], keeping its aspect ratio. there's no need to
+/// look at every pixel of a huge image to find its colors
+fn shrink(image: DynamicImage) -> DynamicImage {
+    if image.width() > MAX_IMAGE_SIZE || image.height() > MAX_IMAGE_SIZE {
+        image.thumbnail(MAX_IMAGE_SIZE, MAX_IMAGE_SIZE)
+    } else {
+        image
+    }
+}
+
+fn color_from_image(bytes: &[u8]) -> Option<RGB<u8>> {
+    let pixels = shrink(image::load_from_memory(bytes).ok()?).to_rgb8();
+
+    let palette = color_thief::get_palette(&pixels, color_thief::ColorFormat::Rgb, 10, 6).ok()?;
+
+    pick_accent(&palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+    use rgb::RGB;
+
+    use super::{color_from_image, pick_accent, shrink, Hsl, Hsv, MAX_IMAGE_SIZE};
+
+    fn encode(image: &RgbImage) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        image
+            .write_to(&mut bytes, ImageFormat::Png)
+            .expect("encoding a png in memory shouldn't fail");
+        bytes.into_inner()
+    }
+
+    /// an image split into vertical stripes of the given colors
+    fn stripes(colors: &[[u8; 3]], width: u32, height: u32) -> Vec<u8> {
+        let stripe_width = width / u32::try_from(colors.len()).expect("not that many colors");
+        encode(&RgbImage::from_fn(width, height, |x, _| {
+            let i = usize::try_from(x / stripe_width).expect("fits in a usize");
+            Rgb(colors[i.min(colors.len() - 1)])
+        }))
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[test]
+    fn hsl_of_primaries() {
+        for color in [
+            RGB::new(255, 0, 0),
+            RGB::new(0, 255, 0),
+            RGB::new(0, 0, 255),
+        ] {
+            let hsl = Hsl::from(color);
+            assert!(
+                close(hsl.saturation, 1.) && close(hsl.lightness, 0.5),
+                "{hsl:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn hsl_of_greys() {
+        let grey = Hsl::from(RGB::new(128, 128, 128));
+        assert!(close(grey.saturation, 0.));
+        assert!(close(grey.lightness, 128. / 255.));
+
+        assert!(close(Hsl::from(RGB::new(0, 0, 0)).lightness, 0.));
+        assert!(close(Hsl::from(RGB::new(255, 255, 255)).lightness, 1.));
+    }
+
+    #[test]
+    fn hsl_of_a_muted_color() {
+        // rgb(191, 64, 64) is hsl(0, 50%, 50%)
+        let hsl = Hsl::from(RGB::new(191, 64, 64));
+        assert!(close(hsl.saturation, 0.5));
+        assert!(close(hsl.lightness, 0.5));
+    }
+
+    #[test]
+    fn hsv_of_colors() {
+        let magenta = Hsv::from(RGB::new(255, 0, 255));
+        assert!(close(magenta.saturation, 1.) && close(magenta.value, 1.));
+
+        // rgb(64, 128, 128) is hsv(180, 50%, 50%)
+        let teal = Hsv::from(RGB::new(64, 128, 128));
+        assert!(close(teal.saturation, 0.5) && close(teal.value, 128. / 255.));
+
+        let dark = Hsv::from(RGB::new(0, 0, 0));
+        assert!(close(dark.saturation, 0.) && close(dark.value, 0.));
+    }
+
+    #[test]
+    fn picks_the_most_vivid_color() {
+        let palette = [
+            RGB::new(120, 110, 100),
+            RGB::new(30, 144, 255),
+            RGB::new(200, 150, 150),
+        ];
+
+        assert_eq!(pick_accent(&palette), Some(RGB::new(30, 144, 255)));
+    }
+
+    #[test]
+    fn skips_black_white_and_grey() {
+        let palette = [
+            RGB::new(5, 0, 10),
+            RGB::new(250, 250, 255),
+            RGB::new(128, 128, 128),
+        ];
+
+        assert_eq!(pick_accent(&palette), None);
+    }
+
+    #[test]
+    fn finds_the_color_in_a_mostly_black_image() {
+        let image = stripes(&[[0, 0, 0], [0, 0, 0], [0, 0, 0], [220, 20, 60]], 128, 64);
+        let color = color_from_image(&image).expect("there's a color in there");
+
+        assert!(color.r > 180 && color.g < 60 && color.b < 100, "{color:?}");
+    }
+
+    #[test]
+    fn finds_the_color_next_to_white() {
+        let image = stripes(&[[255, 255, 255], [255, 255, 255], [40, 180, 90]], 96, 64);
+        let color = color_from_image(&image).expect("there's a color in there");
+
+        assert!(color.g > 140 && color.r < 80 && color.b < 130, "{color:?}");
+    }
+
+    #[test]
+    fn nothing_usable_in_greyscale_images() {
+        let image = stripes(&[[0, 0, 0], [128, 128, 128], [255, 255, 255]], 96, 64);
+
+        assert_eq!(color_from_image(&image), None);
+    }
+
+    #[test]
+    fn big_images_get_shrunk() {
+        let shrunk = shrink(DynamicImage::new_rgb8(2048, 1024));
+        assert_eq!(
+            (shrunk.width(), shrunk.height()),
+            (MAX_IMAGE_SIZE, MAX_IMAGE_SIZE / 2)
+        );
+
+        let tall = shrink(DynamicImage::new_rgb8(300, 1200));
+        assert_eq!(tall.height(), MAX_IMAGE_SIZE);
+        assert!(tall.width() < MAX_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn small_images_are_left_alone() {
+        let image = shrink(DynamicImage::new_rgb8(200, 100));
+
+        assert_eq!((image.width(), image.height()), (200, 100));
+    }
+
+    #[test]
+    fn finds_the_color_in_a_big_image() {
+        let image = stripes(&[[10, 10, 10], [255, 140, 0]], 2048, 1024);
+        let color = color_from_image(&image).expect("there's a color in there");
+
+        assert!(color.r > 200 && color.g > 100 && color.b < 60, "{color:?}");
+    }
+
+    #[test]
+    fn garbage_isnt_an_image() {
+        assert_eq!(color_from_image(b"definitely not a png"), None);
+    }
+}
@@ -1,10 +1,17 @@
-use poise::{send_application_reply, CreateReply};
+use std::time::Duration;
+
+use poise::{send_application_reply, serenity_prelude::GuildId, CreateReply};
+use songbird::{tracks::PlayMode, Call};
 
 use crate::{
     commands::music::{
-        get_client, get_color_from_thumbnail, make_now_playing_embed, SkipVotes, TrackMetadata,
+        attachments::get_cover, color::get_color_from_thumbnail, enqueue_track,
+        filters::FilterPreset, get_client, get_filter, get_loop_mode, get_user_call,
+        limits::apply_limits, make_now_playing_embed, panel::refresh_panel, persist_queue,
+        set_track_volume, vote_skip, LoopMode, PendingTrack, QuickLeave, Restarted, TrackMetadata,
         TrackRequester,
     },
+    data::MusicSettings,
     local_get, Context, Error,
 };
 
@@ -38,6 +45,8 @@ pub async fn now_playing(ctx: Context<'_>) -> Result<(), Error> {
 
     ctx.defer_ephemeral().await?;
 
+    let settings = ctx.data.database.get_music_settings(&guild.id).await?;
+
     let manager = get_client(&ctx).await;
 
     let handler_lock = manager.get(guild.id).unwrap();
@@ -55,12 +64,33 @@ pub async fn now_playing(ctx: Context<'_>) -> Result<(), Error> {
                 .get::<TrackMetadata>()
                 .expect("tracks must ALWAYS have metadata");
             let requester = typemap.get::<TrackRequester>();
-            let color = get_color_from_thumbnail(metadata).await;
-            send_application_reply(
-                ctx,
-                CreateReply::default().embed(make_now_playing_embed(metadata, color, requester)),
-            )
-            .await?;
+            let color = get_color_from_thumbnail(metadata)
+                .await
+                .or_else(|| settings.default_color());
+            let loop_mode = get_loop_mode(&ctx.data.music_sessions, &guild.id).await;
+            let filter = get_filter(&ctx.data.music_sessions, &guild.id).await;
+            let state = current.get_info().await.ok();
+            let paused = state
+                .as_ref()
+                .is_some_and(|state| matches!(state.playing, PlayMode::Pause));
+            let mut embed = make_now_playing_embed(
+                metadata,
+                color,
+                requester,
+                loop_mode,
+                filter,
+                paused,
+                state.map(|state| state.position),
+            );
+            let mut reply = CreateReply::default();
+            if metadata.thumbnail.is_none() {
+                if let Some(cover) = get_cover(metadata).await {
+                    embed = embed.thumbnail(cover.attachment_url());
+                    reply = reply.attachment(cover.attachment());
+                }
+            }
+
+            send_application_reply(ctx, reply.embed(embed)).await?;
         }
     } else {
         send_application_reply(
@@ -122,31 +152,27 @@ pub async fn skip(ctx: Context<'_>) -> Result<(), Error> {
                     .filter(|e| e.1.channel_id.is_some_and(|c| c == bot_channel.0.get()))
                     .count();
 
-                let mut typemap = current_track.typemap().write().await;
-                if let Some(votes) = typemap.get_mut::<SkipVotes>() {
-                    if !votes.contains(&ctx.author().id.get()) {
-                        votes.push(ctx.author().id.get());
-                        if votes.len() == (users_in_channel / 2) {
-                            let _ = handler.queue().skip();
-                            drop(handler);
-                        }
-                        send_application_reply(
-                            ctx,
-                            CreateReply::default().content(local_get(
-                                &ctx.data.translator,
-                                "commands_music_controls_skip_success",
-                                locale,
-                            )),
-                        )
-                        .await?;
-                    }
-                } else {
-                    typemap.insert::<SkipVotes>(vec![]);
+                let (skipped, votes, needed) =
+                    vote_skip(&handler, &current_track, ctx.author().id, users_in_channel).await;
+                drop(handler);
 
-                    typemap
-                        .get_mut::<SkipVotes>()
-                        .expect("skip votes was literally just inserted");
+                let content = if skipped {
+                    local_get(
+                        &ctx.data.translator,
+                        "commands_music_controls_skip_success",
+                        locale,
+                    )
+                } else {
+                    local_get(
+                        &ctx.data.translator,
+                        "commands_music_controls_skip_voted",
+                        locale,
+                    )
+                    .replace("{votes}", &votes.to_string())
+                    .replace("{needed}", &needed.to_string())
                 };
+
+                send_application_reply(ctx, CreateReply::default().content(content)).await?;
             } else {
                 send_application_reply(
                     ctx,
@@ -183,3 +209,460 @@ pub async fn skip(ctx: Context<'_>) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[poise::command(slash_command, ephemeral, guild_only, rename = "loop")]
+pub async fn loop_mode(ctx: Context<'_>, mode: LoopMode) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+    ctx.data
+        .music_sessions
+        .lock()
+        .await
+        .entry(guild_id)
+        .or_default()
+        .loop_mode = mode;
+
+    if let Some(current) = handler_lock.lock().await.queue().current() {
+        let _ = if mode == LoopMode::Track {
+            current.enable_loop()
+        } else {
+            current.disable_loop()
+        };
+    }
+
+    refresh_panel(
+        &ctx.serenity_context().http,
+        &get_client(&ctx).await,
+        &ctx.data.music_sessions,
+        guild_id,
+    )
+    .await;
+
+    let key = match mode {
+        LoopMode::Off => "commands_music_controls_loop_off",
+        LoopMode::Track => "commands_music_controls_loop_track",
+        LoopMode::Queue => "commands_music_controls_loop_queue",
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// applies an audio filter to everything played from now on. the current track picks up where it was
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn filter(ctx: Context<'_>, preset: FilterPreset) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+    ctx.data
+        .music_sessions
+        .lock()
+        .await
+        .entry(guild_id)
+        .or_default()
+        .filter = preset;
+
+    // filters are applied when a track starts, so the current one has to be started over
+    let settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    let mut handler = handler_lock.lock().await;
+    restart_current(&ctx, &mut handler, guild_id, &settings).await;
+    drop(handler);
+
+    let content = if preset == FilterPreset::Off {
+        local_get(
+            &ctx.data.translator,
+            "commands_music_controls_filter_off",
+            locale,
+        )
+    } else {
+        local_get(
+            &ctx.data.translator,
+            "commands_music_controls_filter_success",
+            locale,
+        )
+        .replace("{filter}", preset.name())
+    };
+
+    send_application_reply(ctx, CreateReply::default().content(content)).await?;
+
+    Ok(())
+}
+
+/// swaps the current track for a fresh copy that picks up where it was. it's left alone if the
+/// guild's limits wouldn't let it be queued again
+async fn restart_current(
+    ctx: &Context<'_>,
+    handler: &mut Call,
+    guild_id: GuildId,
+    settings: &MusicSettings,
+) {
+    let Some(current) = handler.queue().current() else {
+        return;
+    };
+    let Ok(state) = current.get_info().await else {
+        return;
+    };
+
+    let typemap = current.typemap().read().await;
+    let (Some(track), Some(requester)) = (
+        PendingTrack::from_typemap(&typemap),
+        typemap.get::<TrackRequester>().cloned(),
+    ) else {
+        return;
+    };
+    let quick_leave = typemap.contains_key::<QuickLeave>();
+    drop(typemap);
+
+    // the current track is being replaced, so it doesn't count against the limits
+    let queue = handler.queue().current_queue();
+    let limited = apply_limits(
+        settings,
+        queue.get(1..).unwrap_or_default(),
+        requester.id,
+        vec![track],
+    )
+    .await;
+    let Some(track) = limited.accepted.into_iter().next() else {
+        return;
+    };
+
+    current
+        .typemap()
+        .write()
+        .await
+        .insert::<Restarted>(Restarted);
+
+    let restarted = enqueue_track(
+        handler,
+        guild_id,
+        &ctx.data.music_sessions,
+        track,
+        requester,
+        quick_leave,
+        state.volume,
+    )
+    .await;
+    let _ = restarted.seek(state.position);
+
+    handler.queue().modify_queue(|vq| {
+        if let Some(track) = vq.pop_back() {
+            vq.insert(1, track);
+        }
+    });
+    let _ = handler.queue().skip();
+
+    if let Err(why) = persist_queue(&ctx.data.database, guild_id, handler).await {
+        tracing::warn!("problem saving queue: {:?}", why);
+    }
+}
+
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn pause(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let current = handler_lock.lock().await.queue().current();
+
+    let key = match current {
+        Some(current) if current.pause().is_ok() => "commands_music_controls_pause_success",
+        _ => "commands_music_controls_pause_failed",
+    };
+
+    refresh_panel(
+        &ctx.serenity_context().http,
+        &get_client(&ctx).await,
+        &ctx.data.music_sessions,
+        ctx.guild_id().expect("no guild for a guild only command?"),
+    )
+    .await;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn resume(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let current = handler_lock.lock().await.queue().current();
+
+    let key = match current {
+        Some(current) if current.play().is_ok() => "commands_music_controls_resume_success",
+        _ => "commands_music_controls_resume_failed",
+    };
+
+    refresh_panel(
+        &ctx.serenity_context().http,
+        &get_client(&ctx).await,
+        &ctx.data.music_sessions,
+        ctx.guild_id().expect("no guild for a guild only command?"),
+    )
+    .await;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// seeks to a timestamp like `1:23` or `83s`, or relative to the current position like `+30s`
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn seek(ctx: Context<'_>, timestamp: String) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(target) = SeekTarget::parse(&timestamp) else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_controls_seek_invalid",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let Some(current) = handler_lock.lock().await.queue().current() else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_controls_skip_notplaying",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let position = match target {
+        SeekTarget::Absolute(position) => position,
+        SeekTarget::Forward(offset) => current.get_info().await?.position + offset,
+        SeekTarget::Backward(offset) => current.get_info().await?.position.saturating_sub(offset),
+    };
+
+    let key = if current.seek_async(position).await.is_ok() {
+        "commands_music_controls_seek_success"
+    } else {
+        "commands_music_controls_seek_failed"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// sets the playback volume in percent for everything in the queue and anything queued later
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn volume(
+    ctx: Context<'_>,
+    #[min = 0]
+    #[max = 200]
+    volume: u8,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+    let mut settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    settings.volume = volume.min(settings.max_volume);
+    ctx.data.database.save_music_settings(&settings).await?;
+
+    for track in handler_lock.lock().await.queue().current_queue() {
+        set_track_volume(&track, settings.volume).await;
+    }
+
+    let key = if volume > settings.max_volume {
+        "commands_music_controls_volume_capped"
+    } else {
+        "commands_music_controls_volume_success"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(&ctx.data.translator, key, locale)
+                .replace("{volume}", &settings.volume.to_string()),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SeekTarget {
+    Absolute(Duration),
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl SeekTarget {
+    /// parses `[[h:]m:]s` (like `1:23` or `1:02:03`) and `83s`, optionally prefixed with `+` or
+    /// `-`. everything after the first field has to be under 60
+    fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let (target, time): (fn(Duration) -> Self, &str) =
+            if let Some(time) = input.strip_prefix('+') {
+                (Self::Forward, time)
+            } else if let Some(time) = input.strip_prefix('-') {
+                (Self::Backward, time)
+            } else {
+                (Self::Absolute, input)
+            };
+
+        let seconds = if let Some(seconds) = time.strip_suffix('s') {
+            number(seconds)?
+        } else {
+            let parts = time.split(':').map(number).collect::<Option<Vec<_>>>()?;
+            let (first, rest) = parts.split_first()?;
+            if rest.len() > 2 || rest.iter().any(|part| *part >= 60) {
+                return None;
+            }
+
+            rest.iter().try_fold(*first, |total, part| {
+                total.checked_mul(60)?.checked_add(*part)
+            })?
+        };
+
+        Some(target(Duration::from_secs(seconds)))
+    }
+}
+
+/// a whole number of digits. `str::parse` would let a sign through too
+fn number(input: &str) -> Option<u64> {
+    if input.is_empty() || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    input.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SeekTarget;
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            SeekTarget::parse("42"),
+            Some(SeekTarget::Absolute(secs(42)))
+        );
+        assert_eq!(
+            SeekTarget::parse("1:23"),
+            Some(SeekTarget::Absolute(secs(83)))
+        );
+        assert_eq!(
+            SeekTarget::parse("1:02:03"),
+            Some(SeekTarget::Absolute(secs(3723)))
+        );
+        assert_eq!(
+            SeekTarget::parse(" 83s "),
+            Some(SeekTarget::Absolute(secs(83)))
+        );
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(
+            SeekTarget::parse("+30s"),
+            Some(SeekTarget::Forward(secs(30)))
+        );
+        assert_eq!(
+            SeekTarget::parse("-1:00"),
+            Some(SeekTarget::Backward(secs(60)))
+        );
+    }
+
+    #[test]
+    fn the_first_field_can_go_past_60() {
+        assert_eq!(
+            SeekTarget::parse("90"),
+            Some(SeekTarget::Absolute(secs(90)))
+        );
+        assert_eq!(
+            SeekTarget::parse("75:00"),
+            Some(SeekTarget::Absolute(secs(4500)))
+        );
+    }
+
+    #[test]
+    fn out_of_range_fields() {
+        assert_eq!(SeekTarget::parse("1:60"), None);
+        assert_eq!(SeekTarget::parse("1:99:00"), None);
+        assert_eq!(SeekTarget::parse("1:00:75"), None);
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(SeekTarget::parse("1:2:3:4"), None);
+        assert_eq!(SeekTarget::parse(""), None);
+        assert_eq!(SeekTarget::parse("+"), None);
+        assert_eq!(SeekTarget::parse("++5"), None);
+        assert_eq!(SeekTarget::parse("1::2"), None);
+        assert_eq!(SeekTarget::parse("1:-2"), None);
+        assert_eq!(SeekTarget::parse("soon"), None);
+        assert_eq!(SeekTarget::parse("99999999999999999999"), None);
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    f32::consts::{FRAC_PI_4, PI, SQRT_2},
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use poise::{serenity_prelude::GuildId, ChoiceParameter};
+use songbird::input::{
+    codecs::{get_codec_registry, get_probe},
+    AudioStream, AudioStreamError, AuxMetadata, Compose, Input, RawAdapter,
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
+    meta::MetadataOptions,
+};
+
+use crate::serenity::async_trait;
+
+use super::{get_filter, MusicSessions};
+
+/// how hard bass boost pushes the low end, in dB
+const BASS_BOOST_GAIN: f32 = 9.;
+const BASS_BOOST_FREQUENCY: f32 = 110.;
+/// bass boost turns everything else down a bit so the boosted lows don't clip
+const BASS_BOOST_HEADROOM: f32 = 0.6;
+/// karaoke keeps everything below this, so removing the vocals doesn't take the bass with it
+const KARAOKE_CROSSOVER: f32 = 200.;
+/// how many times a second 8d audio goes around your head
+const EIGHT_D_ROTATION: f32 = 0.125;
+const NIGHTCORE_SPEED: f32 = 1.25;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ChoiceParameter)]
+pub enum FilterPreset {
+    #[default]
+    #[name = "off"]
+    Off,
+    #[name = "bass boost"]
+    BassBoost,
+    #[name = "nightcore"]
+    Nightcore,
+    #[name = "8d"]
+    EightD,
+    #[name = "karaoke"]
+    Karaoke,
+}
+
+/// wraps a track's input so that whatever filter the guild has picked gets applied to it.
+/// the filter is looked up when the track starts, not when it's queued, so changing it
+/// affects everything that hasn't started yet
+pub struct FilteredInput {
+    inner: Box<dyn Compose>,
+    /// what was already found out about the track when it was queued. songbird asks for this
+    /// while the call is locked, so it shouldn't mean another trip to yt-dlp
+    metadata: AuxMetadata,
+    sessions: Arc<MusicSessions>,
+    guild: GuildId,
+}
+
+impl FilteredInput {
+    pub fn new(
+        inner: Box<dyn Compose>,
+        metadata: AuxMetadata,
+        sessions: Arc<MusicSessions>,
+        guild: GuildId,
+    ) -> Self {
+        Self {
+            inner,
+            metadata,
+            sessions,
+            guild,
+        }
+    }
+}
+
+impl From<FilteredInput> for Input {
+    fn from(val: FilteredInput) -> Self {
+        Self::Lazy(Box::new(val))
+    }
+}
+
+#[async_trait]
+impl Compose for FilteredInput {
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+
+    async fn create_async(
+        &mut self,
+    ) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        let stream = if self.inner.should_create_async() {
+            self.inner.create_async().await?
+        } else {
+            self.inner.create()?
+        };
+
+        let preset = get_filter(&self.sessions, &self.guild).await;
+        if preset == FilterPreset::Off {
+            return Ok(stream);
+        }
+
+        // probing reads from the stream, which blocks
+        tokio::task::spawn_blocking(move || filter_stream(stream, preset))
+            .await
+            .map_err(|why| AudioStreamError::Fail(Box::new(why)))?
+    }
+
+    fn should_create_async(&self) -> bool {
+        true
+    }
+
+    async fn aux_metadata(&mut self) -> Result<AuxMetadata, AudioStreamError> {
+        Ok(self.metadata.clone())
+    }
+}
+
+/// decodes a stream with symphonia and hands songbird the filtered pcm instead
+fn filter_stream(
+    stream: AudioStream<Box<dyn MediaSource>>,
+    preset: FilterPreset,
+) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+    let probed = get_probe()
+        .format(
+            &stream.hint.unwrap_or_default(),
+            MediaSourceStream::new(stream.input, MediaSourceStreamOptions::default()),
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|why| AudioStreamError::Fail(Box::new(why)))?;
+
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(AudioStreamError::Unsupported)?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate.unwrap_or(48_000);
+    let decoder = get_codec_registry()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|why| AudioStreamError::Fail(Box::new(why)))?;
+
+    let source = FilteredSource {
+        format: probed.format,
+        decoder,
+        track_id,
+        samples: None,
+        dsp: Dsp::new(preset, sample_rate),
+        stereo: vec![],
+        out: vec![],
+        out_pos: 0,
+    };
+
+    // nightcore is just the track played back faster. claiming the audio has a higher
+    // sample rate than it really does gets songbird's resampler to do that for us
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let reported_rate = if preset == FilterPreset::Nightcore {
+        (sample_rate as f32 * NIGHTCORE_SPEED) as u32
+    } else {
+        sample_rate
+    };
+
+    Ok(AudioStream {
+        input: Box::new(RawAdapter::new(source, reported_rate, 2)),
+        hint: None,
+    })
+}
+
+/// a decoded track, read out as interleaved stereo `f32` pcm with a filter applied
+struct FilteredSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    samples: Option<SampleBuffer<f32>>,
+    dsp: Dsp,
+    stereo: Vec<f32>,
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl FilteredSource {
+    /// decodes and filters the next packet into `out`. returns false once the track is over
+    fn decode_next(&mut self) -> io::Result<bool> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(why)) if why.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(why) => return Err(io::Error::other(why)),
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // a corrupt packet isn't worth ending the whole track over
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(why) => return Err(io::Error::other(why)),
+            };
+
+            if decoded.frames() == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+            let samples = match &mut self.samples {
+                Some(samples) if samples.capacity() >= decoded.capacity() * channels => samples,
+                samples => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+            };
+            samples.copy_interleaved_ref(decoded);
+
+            // everything comes out as stereo: mono gets doubled up, anything past 2 channels is dropped
+            self.stereo.clear();
+            for frame in samples.samples().chunks_exact(channels) {
+                let left = frame[0];
+                self.stereo.push(left);
+                self.stereo.push(frame.get(1).copied().unwrap_or(left));
+            }
+            self.dsp.process(&mut self.stereo);
+
+            self.out.clear();
+            self.out_pos = 0;
+            for sample in &self.stereo {
+                self.out.extend_from_slice(&sample.to_le_bytes());
+            }
+
+            return Ok(true);
+        }
+    }
+}
+
+impl Read for FilteredSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos >= self.out.len() {
+            if !self.decode_next()? {
+                return Ok(0);
+            }
+        }
+
+        let count = buf.len().min(self.out.len() - self.out_pos);
+        buf[..count].copy_from_slice(&self.out[self.out_pos..self.out_pos + count]);
+        self.out_pos += count;
+
+        Ok(count)
+    }
+}
+
+impl Seek for FilteredSource {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        // songbird recreates the input and skips forward instead
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+impl MediaSource for FilteredSource {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// a standard biquad filter, see https://www.w3.org/TR/audio-eq-cookbook/
+#[derive(Clone, Copy, Default)]
+pub(super) struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    /// a filter from coefficients that are already divided through by a0
+    pub(super) const fn new(b: [f32; 3], a: [f32; 2]) -> Self {
+        Self {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            a1: a[0],
+            a2: a[1],
+            x1: 0.,
+            x2: 0.,
+            y1: 0.,
+            y2: 0.,
+        }
+    }
+
+    fn low_shelf(sample_rate: f32, frequency: f32, gain_db: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.);
+        let w0 = 2. * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        // shelf slope of 1
+        let alpha = sin / 2. * SQRT_2;
+        let sqrt_a_alpha = 2. * a.sqrt() * alpha;
+
+        let a0 = (a - 1.).mul_add(cos, a + 1.) + sqrt_a_alpha;
+
+        Self {
+            b0: a * ((a - 1.).mul_add(-cos, a + 1.) + sqrt_a_alpha) / a0,
+            b1: 2. * a * (a + 1.).mul_add(-cos, a - 1.) / a0,
+            b2: a * ((a - 1.).mul_add(-cos, a + 1.) - sqrt_a_alpha) / a0,
+            a1: -2. * (a + 1.).mul_add(cos, a - 1.) / a0,
+            a2: ((a - 1.).mul_add(cos, a + 1.) - sqrt_a_alpha) / a0,
+            ..Self::default()
+        }
+    }
+
+    pub(super) fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0.mul_add(
+            x,
+            self.b1.mul_add(
+                self.x1,
+                self.b2
+                    .mul_add(self.x2, (-self.a1).mul_add(self.y1, -self.a2 * self.y2)),
+            ),
+        );
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+}
+
+/// the per-track state for whichever filter is applied
+struct Dsp {
+    preset: FilterPreset,
+    sample_rate: f32,
+    shelves: [Biquad; 2],
+    /// one pole low pass state for karaoke
+    low: f32,
+    low_coefficient: f32,
+    /// where the sound is on its way around your head, for 8d
+    phase: f32,
+}
+
+impl Dsp {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(preset: FilterPreset, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let shelf = Biquad::low_shelf(sample_rate, BASS_BOOST_FREQUENCY, BASS_BOOST_GAIN);
+
+        Self {
+            preset,
+            sample_rate,
+            shelves: [shelf; 2],
+            low: 0.,
+            low_coefficient: 1. - (-2. * PI * KARAOKE_CROSSOVER / sample_rate).exp(),
+            phase: 0.,
+        }
+    }
+
+    /// filters interleaved stereo samples in place
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let (left, right) = (frame[0], frame[1]);
+
+            let (left, right) = match self.preset {
+                // nightcore is handled by the sample rate, see `filter_stream`
+                FilterPreset::Off | FilterPreset::Nightcore => (left, right),
+                FilterPreset::BassBoost => (
+                    self.shelves[0].process(left) * BASS_BOOST_HEADROOM,
+                    self.shelves[1].process(right) * BASS_BOOST_HEADROOM,
+                ),
+                FilterPreset::Karaoke => {
+                    // vocals are usually mixed dead center, so cancelling out whatever both
+                    // channels have in common removes them. the bass gets added back after
+                    let mid = (left + right) / 2.;
+                    self.low = self.low_coefficient.mul_add(mid - self.low, self.low);
+                    (left - right + self.low, right - left + self.low)
+                }
+                FilterPreset::EightD => {
+                    self.phase =
+                        (self.phase + 2. * PI * EIGHT_D_ROTATION / self.sample_rate) % (2. * PI);
+                    // equal power panning, from hard left to hard right and back
+                    let angle = (self.phase.sin() + 1.) * FRAC_PI_4;
+                    let mid = (left + right) / 2. * SQRT_2;
+                    (mid * angle.cos(), mid * angle.sin())
+                }
+            };
+
+            frame[0] = left;
+            frame[1] = right;
+        }
+    }
+}
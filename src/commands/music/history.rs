@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use itertools::Itertools;
+use poise::{
+    send_application_reply,
+    serenity_prelude::{CreateEmbed, CreateEmbedFooter},
+    CreateReply,
+};
+
+use crate::{
+    commands::music::{
+        enqueue_track, format_duration, get_requester, get_user_call,
+        library::{display_url, is_linkable},
+        limits::apply_limits,
+        persist_queue,
+        playback::{_play, PlayRequest},
+        send_paginated, volume_to_gain, PendingTrack, Restarted,
+    },
+    data::HistoryEntry,
+    local_get, Context, Error,
+};
+
+/// how far back `/music history` and `/music replay` can go
+const HISTORY_LIMIT: i64 = 100;
+const PAGE_SIZE: usize = 10;
+
+fn history_line(position: usize, entry: &HistoryEntry) -> String {
+    let title = entry
+        .track
+        .title
+        .clone()
+        .unwrap_or_else(|| display_url(&entry.track.url));
+    let title = if is_linkable(&entry.track.url) {
+        format!("[{title}]({})", entry.track.url)
+    } else {
+        title
+    };
+    let duration = entry.track.duration_ms.map_or_else(
+        || "?".to_string(),
+        |d| format_duration(Duration::from_millis(d)),
+    );
+    let requester = entry.requester_name.as_deref().unwrap_or("-");
+
+    format!(
+        "**{position}.** {title} `{duration}` - {requester} <t:{}:R>",
+        entry.played_at.timestamp_millis() / 1000
+    )
+}
+
+/// shows what has recently been played in this server
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn history(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let entries = ctx
+        .data
+        .database
+        .get_history(&guild_id, HISTORY_LIMIT)
+        .await?;
+
+    if entries.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_history_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    let page_count = entries.len().div_ceil(PAGE_SIZE);
+
+    send_paginated(&ctx, page_count, |page| {
+        CreateEmbed::new()
+            .title("History:")
+            .description(
+                entries
+                    .iter()
+                    .enumerate()
+                    .skip(page * PAGE_SIZE)
+                    .take(PAGE_SIZE)
+                    .map(|(i, entry)| history_line(i + 1, entry))
+                    .join("\n"),
+            )
+            .footer(CreateEmbedFooter::new(format!(
+                "Page {}/{page_count} • use /music replay to queue one again",
+                page + 1
+            )))
+    })
+    .await
+}
+
+/// queues a track from /music history again. 1 is the most recently played track
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn replay(
+    ctx: Context<'_>,
+    #[min = 1]
+    #[max = 100]
+    position: usize,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let entries = ctx
+        .data
+        .database
+        .get_history(&guild_id, HISTORY_LIMIT)
+        .await?;
+
+    let Some(track) = entries
+        .get(position - 1)
+        .and_then(|entry| PendingTrack::from_saved(&entry.track))
+    else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_history_invalidposition",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    _play(ctx, PlayRequest::Tracks(vec![track]), quick_leave).await
+}
+
+/// goes back to the last track that finished playing, and one further back each time after that
+#[poise::command(slash_command, ephemeral, guild_only)]
+pub async fn previous(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let cursor = ctx
+        .data
+        .music_sessions
+        .lock()
+        .await
+        .get(&guild_id)
+        .map_or(0, |session| session.history_cursor);
+
+    let Some(track) = ctx
+        .data
+        .database
+        .get_history(&guild_id, HISTORY_LIMIT)
+        .await?
+        .get(cursor)
+        .and_then(|entry| PendingTrack::from_saved(&entry.track))
+    else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_history_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let settings = ctx.data.database.get_music_settings(&guild_id).await?;
+    let requester = get_requester(&ctx).await;
+
+    let mut handler = handler_lock.lock().await;
+
+    // the current track is about to be skipped, so it doesn't count against the limits
+    let queue = handler.queue().current_queue();
+    let limited = apply_limits(
+        &settings,
+        queue.get(1..).unwrap_or_default(),
+        ctx.author().id,
+        vec![track],
+    )
+    .await;
+    let Some(track) = limited.accepted.into_iter().next() else {
+        drop(handler);
+
+        if let Some(reason) = limited.reason {
+            send_application_reply(
+                ctx,
+                CreateReply::default().content(reason.explain(&ctx.data.translator, locale)),
+            )
+            .await?;
+        }
+
+        return Ok(());
+    };
+
+    enqueue_track(
+        &mut handler,
+        guild_id,
+        &ctx.data.music_sessions,
+        track,
+        requester,
+        false,
+        volume_to_gain(settings.volume),
+    )
+    .await;
+
+    // put it right behind the current track, then skip to it. the skipped track isn't history,
+    // or going back again would just return to it
+    if let Some(current) = handler
+        .queue()
+        .current()
+        .filter(|_| handler.queue().len() > 1)
+    {
+        current
+            .typemap()
+            .write()
+            .await
+            .insert::<Restarted>(Restarted);
+        handler.queue().modify_queue(|vq| {
+            if let Some(track) = vq.pop_back() {
+                vq.insert(1, track);
+            }
+        });
+        let _ = handler.queue().skip();
+    }
+
+    if let Err(why) = persist_queue(&ctx.data.database, guild_id, &handler).await {
+        tracing::warn!("problem saving queue: {:?}", why);
+    }
+    drop(handler);
+
+    ctx.data
+        .music_sessions
+        .lock()
+        .await
+        .entry(guild_id)
+        .or_default()
+        .history_cursor = cursor + 1;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(
+            &ctx.data.translator,
+            "commands_music_history_previous_success",
+            locale,
+        )),
+    )
+    .await?;
+
+    Ok(())
+}
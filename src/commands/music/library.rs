@@ -0,0 +1,589 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+use poise::{send_application_reply, serenity_prelude::CreateEmbed, CreateReply};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    commands::music::{
+        attachments::{probe_file, read_local},
+        format_duration,
+        playback::{_play, PlayRequest},
+        PendingTrack,
+    },
+    data::{Database, LibraryTrack},
+    local_get, Context, Error,
+};
+
+/// how many tracks `/music library search` lists
+const SEARCH_LIMIT: i64 = 10;
+
+/// the library directory, once it's been scanned. tracks are only ever played from in here
+static LIBRARY_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// indexes every file in `root` that we can play, so the library can be searched without going
+/// to the disk. files that haven't changed since the last scan aren't probed again
+pub async fn scan_library(database: Arc<Database>, root: PathBuf) {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(why) => {
+            tracing::error!("can't find music library at {}: {:?}", root.display(), why);
+            return;
+        }
+    };
+    let _ = LIBRARY_ROOT.set(root.clone());
+
+    let known = match database.get_library().await {
+        Ok(tracks) => tracks
+            .into_iter()
+            .map(|track| (track.path.clone(), track))
+            .collect(),
+        Err(why) => {
+            tracing::warn!("problem loading music library index: {:?}", why);
+            HashMap::new()
+        }
+    };
+
+    let (changed, paths) = match tokio::task::spawn_blocking(move || scan(&root, &known)).await {
+        Ok(scanned) => scanned,
+        Err(why) => {
+            tracing::error!("music library scan failed: {:?}", why);
+            return;
+        }
+    };
+
+    for track in &changed {
+        if let Err(why) = database.save_library_track(track).await {
+            tracing::warn!("problem saving library track {}: {:?}", track.path, why);
+        }
+    }
+
+    if let Err(why) = database.prune_library(&paths).await {
+        tracing::warn!("problem removing deleted library tracks: {:?}", why);
+    }
+
+    tracing::info!(
+        "music library has {} tracks, {} new or changed",
+        paths.len(),
+        changed.len()
+    );
+}
+
+/// walks the library, returning the tracks that need (re)indexing and the paths of everything
+/// that's still there
+fn scan(root: &Path, known: &HashMap<String, LibraryTrack>) -> (Vec<LibraryTrack>, Vec<String>) {
+    let mut files = vec![];
+    collect_files(root, &mut files);
+
+    let mut changed = vec![];
+    let mut paths = vec![];
+    let mut hashes = HashSet::new();
+
+    for file in files {
+        let Some(path) = file
+            .strip_prefix(root)
+            .ok()
+            .and_then(Path::to_str)
+            .map(ToString::to_string)
+        else {
+            tracing::warn!("skipping {}, its name isn't valid utf-8", file.display());
+            continue;
+        };
+
+        let (size, modified_ms) = match fs::metadata(&file) {
+            Ok(stat) => (stat.len(), modified_ms(&stat)),
+            Err(why) => {
+                tracing::warn!("problem reading {}: {:?}", file.display(), why);
+                continue;
+            }
+        };
+        let indexed = known.get(&path);
+        let unchanged = indexed
+            .filter(|track| track.size == size && modified_ms.is_some())
+            .filter(|track| track.modified_ms == modified_ms);
+
+        let hash = match unchanged {
+            Some(track) => track.hash.clone(),
+            None => match hash_file(&file) {
+                Ok(hash) => hash,
+                Err(why) => {
+                    tracing::warn!("problem reading {}: {:?}", file.display(), why);
+                    continue;
+                }
+            },
+        };
+
+        // the same file under two names would just show up twice in searches
+        if !hashes.insert(hash.clone()) {
+            tracing::debug!("skipping {}, it's a copy of another track", path);
+            continue;
+        }
+
+        if unchanged.is_some() {
+            paths.push(path);
+            continue;
+        }
+
+        // touched, but the same as it was, so its tags don't need reading again
+        let track = match indexed.filter(|track| track.hash == hash) {
+            Some(track) => LibraryTrack {
+                size,
+                modified_ms,
+                ..track.clone()
+            },
+            None => match probe_file(&file) {
+                Ok((metadata, _)) => LibraryTrack {
+                    title: metadata.title.unwrap_or_else(|| path.clone()),
+                    artist: metadata.artist,
+                    album: metadata.album,
+                    duration_ms: metadata
+                        .duration
+                        .and_then(|d| u64::try_from(d.as_millis()).ok()),
+                    path: path.clone(),
+                    hash,
+                    size,
+                    modified_ms,
+                },
+                Err(why) => {
+                    tracing::info!("skipping {}, it isn't playable: {:?}", path, why);
+                    continue;
+                }
+            },
+        };
+        changed.push(track);
+        paths.push(path);
+    }
+
+    (changed, paths)
+}
+
+fn modified_ms(stat: &fs::Metadata) -> Option<u64> {
+    let modified = stat.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    u64::try_from(modified.as_millis()).ok()
+}
+
+/// finds every file under `dir`. symlinks are skipped so a loop can't keep us here forever
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(why) => {
+            tracing::warn!("problem reading {}: {:?}", dir.display(), why);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => collect_files(&entry.path(), files),
+            Ok(kind) if kind.is_file() => files.push(entry.path()),
+            _ => {}
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// the file a track's url points to, as long as it's inside the library. file urls for anything
+/// outside of it get no special treatment, so they can't be used to play whatever is on the disk
+pub(super) fn local_path(url: &Url) -> Option<PathBuf> {
+    path_in(LIBRARY_ROOT.get()?, url)
+}
+
+/// [`local_path`], for a library at `root`
+fn path_in(root: &Path, url: &Url) -> Option<PathBuf> {
+    if url.scheme() != "file" {
+        return None;
+    }
+
+    // canonicalizing gets rid of any ../ that would lead out of the library
+    let path = url.to_file_path().ok()?.canonicalize().ok()?;
+
+    path.starts_with(root).then_some(path)
+}
+
+/// whether a track's url can be linked to in chat. file urls are no use to anyone but the bot, and
+/// would give away where things are on the disk
+pub(super) fn is_linkable(url: &str) -> bool {
+    !url.starts_with("file:")
+}
+
+/// how a track's url is shown in chat. library files are shown by where they are in the library
+pub(super) fn display_url(url: &str) -> String {
+    if is_linkable(url) {
+        return url.to_string();
+    }
+
+    let path = Url::parse(url).ok().and_then(|url| url.to_file_path().ok());
+    LIBRARY_ROOT
+        .get()
+        .zip(path)
+        .and_then(|(root, path)| Some(path.strip_prefix(root).ok()?.display().to_string()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn track_line(position: usize, track: &LibraryTrack) -> String {
+    let duration = track.duration_ms.map_or_else(
+        || "?".to_string(),
+        |d| format_duration(Duration::from_millis(d)),
+    );
+
+    match &track.artist {
+        Some(artist) => format!("**{position}.** {} - {artist} `{duration}`", track.title),
+        None => format!("**{position}.** {} `{duration}`", track.title),
+    }
+}
+
+/// replies that there's no library, if there isn't one. returns whether there is
+async fn check_library(ctx: &Context<'_>) -> Result<bool, Error> {
+    if LIBRARY_ROOT.get().is_some() {
+        return Ok(true);
+    }
+
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    send_application_reply(
+        *ctx,
+        CreateReply::default().content(local_get(
+            &ctx.data.translator,
+            "commands_music_library_disabled",
+            locale,
+        )),
+    )
+    .await?;
+
+    Ok(false)
+}
+
+/// queues a track from the library, reading its tags again in case the file changed since the
+/// last scan
+async fn play_track(
+    ctx: Context<'_>,
+    track: &LibraryTrack,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let path = LIBRARY_ROOT
+        .get()
+        .expect("the library was checked for already")
+        .join(&track.path);
+    let url = Url::from_file_path(&path).ok();
+
+    let metadata = match &url {
+        Some(url) => read_local(url, path).await.ok(),
+        None => None,
+    };
+
+    let (Some(url), Some(metadata)) = (url, metadata) else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_library_missing",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    _play(
+        ctx,
+        PlayRequest::Tracks(vec![PendingTrack::new(url, metadata)]),
+        quick_leave,
+    )
+    .await
+}
+
+#[poise::command(slash_command, subcommands("search", "play", "random"))]
+#[allow(clippy::unused_async)]
+pub async fn library(_: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+async fn autocomplete_title(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    ctx.data
+        .database
+        .search_library(partial, 25)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|track| track.title)
+        .unique()
+        .collect()
+}
+
+/// looks for tracks in the library by title or artist
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn search(ctx: Context<'_>, query: String) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    if !check_library(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    let tracks = ctx
+        .data
+        .database
+        .search_library(&query, SEARCH_LIMIT)
+        .await?;
+
+    if tracks.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_library_noresults",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().embed(
+            CreateEmbed::new().title("Library:").description(
+                tracks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, track)| track_line(i + 1, track))
+                    .join("\n"),
+            ),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// queues a track from the library
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn play(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_title"] title: String,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    if !check_library(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    let tracks = ctx.data.database.search_library(&title, 25).await?;
+    // autocomplete gives us the whole title, but anything typed by hand just gets the best match
+    let Some(track) = tracks
+        .iter()
+        .find(|track| track.title.eq_ignore_ascii_case(&title))
+        .or_else(|| tracks.first())
+    else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_library_noresults",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    play_track(ctx, track, quick_leave).await
+}
+
+/// queues a random track from the library
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn random(ctx: Context<'_>, quick_leave: Option<bool>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    if !check_library(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    let Some(track) = ctx.data.database.random_library_track().await? else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_library_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    play_track(ctx, &track, quick_leave).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use url::Url;
+
+    use crate::data::LibraryTrack;
+
+    use super::{modified_ms, path_in, scan};
+
+    /// how many samples are in the test wav, a tenth of a second's worth
+    const SAMPLES: u16 = 800;
+
+    /// a fresh directory for a test to put its files in, with a library inside it. it's deleted
+    /// again when the test is done with it
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("drcbot-library-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("library")).expect("the temp dir should be writable");
+
+            Self(dir.canonicalize().expect("the temp dir was just made"))
+        }
+
+        fn library(&self) -> PathBuf {
+            self.0.join("library")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// a short, silent 8khz mono wav file
+    fn wav() -> Vec<u8> {
+        let data_len = u32::from(SAMPLES) * 2;
+
+        let mut wav = vec![];
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        // pcm, one channel
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        // sample rate, byte rate, block size and bits per sample
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.resize(wav.len() + usize::from(SAMPLES) * 2, 0);
+
+        wav
+    }
+
+    fn write(path: &Path, contents: &[u8]) {
+        fs::write(path, contents).expect("the temp dir should be writable");
+    }
+
+    #[test]
+    fn copies_are_indexed_once() {
+        let dir = TempDir::new("copies");
+        let root = dir.library();
+        write(&root.join("a.wav"), &wav());
+        write(&root.join("b.wav"), &wav());
+
+        let (changed, paths) = scan(&root, &HashMap::new());
+
+        assert_eq!(changed.len(), 1);
+        assert_eq!(paths.len(), 1);
+    }
+
+    #[test]
+    fn unchanged_files_arent_read_again() {
+        let dir = TempDir::new("unchanged");
+        let root = dir.library();
+        let file = root.join("song.wav");
+        // this would be skipped as unplayable if it got read
+        write(&file, b"not audio");
+        let stat = fs::metadata(&file).expect("the file was just written");
+
+        let indexed = LibraryTrack {
+            path: "song.wav".to_string(),
+            hash: "from the last scan".to_string(),
+            title: "song".to_string(),
+            artist: None,
+            album: None,
+            duration_ms: None,
+            size: stat.len(),
+            modified_ms: modified_ms(&stat),
+        };
+        let known = HashMap::from([(indexed.path.clone(), indexed)]);
+
+        let (changed, paths) = scan(&root, &known);
+
+        assert!(changed.is_empty());
+        assert_eq!(paths, ["song.wav"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn only_files_in_the_library_are_found() {
+        let dir = TempDir::new("escape");
+        let root = dir.library();
+        let inside = root.join("song.wav");
+        let outside = dir.0.join("secret.wav");
+        write(&inside, &wav());
+        write(&outside, &wav());
+        std::os::unix::fs::symlink(&outside, root.join("link.wav"))
+            .expect("the temp dir should be writable");
+
+        let url = |path: &Path| Url::from_file_path(path).expect("temp paths are absolute");
+
+        assert_eq!(path_in(&root, &url(&inside)), Some(inside));
+        assert_eq!(path_in(&root, &url(&outside)), None);
+        assert_eq!(path_in(&root, &url(&root.join("link.wav"))), None);
+
+        let escape =
+            Url::parse(&format!("{}/../secret.wav", url(&root))).expect("this is still a file url");
+        assert_eq!(path_in(&root, &escape), None);
+
+        let web = Url::parse("https://example.com/song.wav").expect("this is a valid url");
+        assert_eq!(path_in(&root, &web), None);
+    }
+}
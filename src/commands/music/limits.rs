@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use poise::serenity_prelude::UserId;
+use songbird::tracks::TrackHandle;
+
+use crate::{data::MusicSettings, local_get, locale::Translator};
+
+use super::{format_duration, PendingTrack, TrackRequester};
+
+/// why a track didn't make it into the queue
+#[derive(Clone, Copy)]
+pub(super) enum Rejection {
+    TooLong(Duration),
+    Livestream,
+    QueueFull(u16),
+    UserQuota(u16),
+}
+
+impl Rejection {
+    /// explains the rejection in a way that can be sent back to whoever asked for the track
+    pub(super) fn explain(self, translator: &Translator, locale: &str) -> String {
+        match self {
+            Self::TooLong(max) => local_get(translator, "commands_music_limits_toolong", locale)
+                .replace("{duration}", &format_duration(max)),
+            Self::Livestream => local_get(translator, "commands_music_limits_livestream", locale),
+            Self::QueueFull(max) => {
+                local_get(translator, "commands_music_limits_queuefull", locale)
+                    .replace("{count}", &max.to_string())
+            }
+            Self::UserQuota(max) => {
+                local_get(translator, "commands_music_limits_userquota", locale)
+                    .replace("{count}", &max.to_string())
+            }
+        }
+    }
+}
+
+pub(super) struct Limited {
+    pub accepted: Vec<PendingTrack>,
+    pub skipped: usize,
+    /// why the first skipped track was skipped
+    pub reason: Option<Rejection>,
+}
+
+/// works out which of `tracks` fit within the guild's limits, given what's already in the queue
+pub(super) async fn apply_limits(
+    settings: &MusicSettings,
+    queue: &[TrackHandle],
+    requester: UserId,
+    tracks: Vec<PendingTrack>,
+) -> Limited {
+    let mut queue_space = settings
+        .max_queue_length
+        .map(|max| usize::from(max).saturating_sub(queue.len()));
+
+    let mut user_space = None;
+    if let Some(max) = settings.max_tracks_per_user {
+        let mut queued = 0;
+        for track in queue {
+            if track
+                .typemap()
+                .read()
+                .await
+                .get::<TrackRequester>()
+                .is_some_and(|r| r.id == requester)
+            {
+                queued += 1;
+            }
+        }
+        user_space = Some(usize::from(max).saturating_sub(queued));
+    }
+
+    let max_duration = settings
+        .max_track_duration
+        .map(|secs| Duration::from_secs(secs.into()));
+
+    let mut limited = Limited {
+        accepted: Vec::with_capacity(tracks.len()),
+        skipped: 0,
+        reason: None,
+    };
+
+    for track in tracks {
+        // plenty of finished tracks don't know how long they are, so only stations count as live
+        let rejection = match track.metadata.duration {
+            _ if track.radio && !settings.allow_livestreams => Some(Rejection::Livestream),
+            Some(duration) if max_duration.is_some_and(|max| duration > max) => {
+                max_duration.map(Rejection::TooLong)
+            }
+            _ if queue_space == Some(0) => settings.max_queue_length.map(Rejection::QueueFull),
+            _ if user_space == Some(0) => settings.max_tracks_per_user.map(Rejection::UserQuota),
+            _ => None,
+        };
+
+        if let Some(rejection) = rejection {
+            limited.skipped += 1;
+            limited.reason.get_or_insert(rejection);
+            continue;
+        }
+
+        queue_space = queue_space.map(|space| space - 1);
+        user_space = user_space.map(|space| space - 1);
+        limited.accepted.push(track);
+    }
+
+    limited
+}
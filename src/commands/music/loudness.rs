@@ -0,0 +1,486 @@
+use std::{
+    collections::HashSet,
+    f64::consts::PI,
+    io::ErrorKind,
+    num::NonZeroUsize,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use lru::LruCache;
+use poise::serenity_prelude::GuildId;
+use songbird::{
+    input::{
+        codecs::{get_codec_registry, get_probe},
+        AudioStream, AudioStreamError,
+    },
+    tracks::TrackHandle,
+    Event, EventContext, EventHandler, Songbird,
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
+    meta::MetadataOptions,
+};
+use tokio::sync::{Mutex, Semaphore};
+use url::Url;
+
+use crate::{
+    data::{Database, MusicSettings, TrackLoudness},
+    serenity::async_trait,
+};
+
+use super::{
+    filters::Biquad, open_source, radio::LiveStream, set_track_volume, DirectSource, TrackGain,
+    TrackMetadata, TrackSource,
+};
+
+/// the most of a track that gets measured. the first quarter hour is close enough, and it keeps
+/// long mixes from holding up everything else
+const MAX_MEASURED: Duration = Duration::from_secs(15 * 60);
+/// how many of the tracks coming up get measured ahead of time
+const PREFETCH: usize = 3;
+/// the most a quiet track gets turned up, in db
+const MAX_BOOST: f64 = 12.;
+/// blocks quieter than this (in LUFS) are silence, and don't count towards the loudness
+const ABSOLUTE_GATE: f64 = -70.;
+/// blocks this much quieter than the average (in LU) are quiet passages, and don't count either
+const RELATIVE_GATE: f64 = -10.;
+
+/// measuring means downloading and decoding the whole track, so only a couple happen at once
+static MEASURING: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(2));
+
+/// urls that have been measured, so nothing gets measured twice. the measurements themselves are
+/// kept in the database
+static MEASURED: LazyLock<Mutex<LruCache<String, ()>>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(1024).expect("cache size isn't zero"),
+    ))
+});
+/// urls being measured right now. anything that fails gets tried again the next time it's queued
+static IN_PROGRESS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+/// measures loudness the way EBU R128 does: k-weighted, in 400ms blocks that overlap by 75%,
+/// gated to leave out silence and quiet passages
+struct LoudnessMeter {
+    /// the k-weighting filters (a high shelf, then a high pass) for each channel
+    filters: Vec<[Biquad; 2]>,
+    /// frames in each 100ms step
+    step: usize,
+    /// frames so far in the current step
+    position: usize,
+    /// k-weighted energy of the current step, summed over channels
+    energy: f64,
+    /// mean square of each finished step
+    steps: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        let rate = f64::from(sample_rate);
+
+        Self {
+            filters: vec![[high_shelf(rate), high_pass(rate)]; channels.max(1)],
+            step: usize::try_from(sample_rate / 10)
+                .unwrap_or(usize::MAX)
+                .max(1),
+            position: 0,
+            energy: 0.,
+            steps: vec![],
+            peak: 0.,
+        }
+    }
+
+    /// adds some interleaved audio to the measurement
+    fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.filters.len()) {
+            for (sample, [shelf, pass]) in frame.iter().zip(&mut self.filters) {
+                self.peak = self.peak.max(sample.abs());
+                let weighted = f64::from(pass.process(shelf.process(*sample)));
+                self.energy = weighted.mul_add(weighted, self.energy);
+            }
+
+            self.position += 1;
+            if self.position == self.step {
+                #[allow(clippy::cast_precision_loss)]
+                self.steps.push(self.energy / self.step as f64);
+                self.position = 0;
+                self.energy = 0.;
+            }
+        }
+    }
+
+    /// the integrated loudness in LUFS and the peak, as long as there was more than silence
+    fn finish(&self) -> Option<(f64, f32)> {
+        let blocks: Vec<f64> = self
+            .steps
+            .windows(4)
+            .map(|steps| steps.iter().sum::<f64>() / 4.)
+            .filter(|block| loudness(*block) > ABSOLUTE_GATE)
+            .collect();
+
+        let threshold = loudness(mean(&blocks)?) + RELATIVE_GATE;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|block| loudness(*block) > threshold)
+            .collect();
+
+        Some((loudness(mean(&gated)?), self.peak))
+    }
+}
+
+/// the first k-weighting stage, which models the head. coefficients are from ITU-R BS.1770,
+/// worked out again for sample rates other than 48khz
+#[allow(clippy::cast_possible_truncation)]
+fn high_shelf(rate: f64) -> Biquad {
+    let (frequency, gain, q) = (
+        1_681.974_450_955_533,
+        3.999_843_853_973_347,
+        0.707_175_236_955_419_6,
+    );
+    let k = (PI * frequency / rate).tan();
+    let k2 = k.powi(2);
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1. + k / q + k2;
+
+    Biquad::new(
+        [
+            ((vh + vb * k / q + k2) / a0) as f32,
+            (2. * (k2 - vh) / a0) as f32,
+            ((vh - vb * k / q + k2) / a0) as f32,
+        ],
+        [
+            (2. * (k2 - 1.) / a0) as f32,
+            ((1. - k / q + k2) / a0) as f32,
+        ],
+    )
+}
+
+/// the second k-weighting stage, which cuts out the lows we barely hear
+#[allow(clippy::cast_possible_truncation)]
+fn high_pass(rate: f64) -> Biquad {
+    let (frequency, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+    let k = (PI * frequency / rate).tan();
+    let k2 = k.powi(2);
+    let a0 = 1. + k / q + k2;
+
+    Biquad::new(
+        [1., -2., 1.],
+        [
+            (2. * (k2 - 1.) / a0) as f32,
+            ((1. - k / q + k2) / a0) as f32,
+        ],
+    )
+}
+
+fn loudness(energy: f64) -> f64 {
+    10f64.mul_add(energy.log10(), -0.691)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// decodes a track and measures it. this blocks, so it shouldn't be called on the runtime
+fn measure(stream: AudioStream<Box<dyn MediaSource>>) -> Option<(f64, f32)> {
+    let mut probed = get_probe()
+        .format(
+            &stream.hint.unwrap_or_default(),
+            MediaSourceStream::new(stream.input, MediaSourceStreamOptions::default()),
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?;
+    let track_id = track.id;
+    let max_frames = MAX_MEASURED.as_secs() * u64::from(track.codec_params.sample_rate?);
+    let mut decoder = get_codec_registry()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .ok()?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    let mut frames = 0;
+
+    while frames < max_frames {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(why)) if why.kind() == ErrorKind::UnexpectedEof => break,
+            // half a measurement would be saved as if it were the whole thing
+            Err(why) => {
+                tracing::debug!("problem reading track to measure: {:?}", why);
+                return None;
+            }
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(why) => {
+                tracing::debug!("problem decoding track to measure: {:?}", why);
+                return None;
+            }
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        frames += decoded.frames() as u64;
+
+        let buffer = match &mut samples {
+            Some(samples) if samples.capacity() >= decoded.capacity() * channels => samples,
+            samples => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+
+        meter
+            .get_or_insert_with(|| LoudnessMeter::new(spec.rate, channels))
+            .push(buffer.samples());
+    }
+
+    meter?.finish()
+}
+
+/// measures the track at `url` in the background, unless that's already happened or is happening
+async fn measure_later(database: Arc<Database>, url: Url, direct: bool) {
+    if MEASURED.lock().await.contains(url.as_str())
+        || !IN_PROGRESS.lock().await.insert(url.to_string())
+    {
+        return;
+    }
+
+    if measure_url(&database, &url, direct).await.is_some() {
+        MEASURED.lock().await.put(url.to_string(), ());
+    }
+    IN_PROGRESS.lock().await.remove(url.as_str());
+}
+
+/// measures the track at `url`, unless the database already knows how loud it is
+async fn measure_url(database: &Database, url: &Url, direct: bool) -> Option<TrackLoudness> {
+    if let Ok(Some(loudness)) = database.get_loudness(url.as_str()).await {
+        return Some(loudness);
+    }
+
+    let _permit = MEASURING.acquire().await.ok()?;
+
+    let mut input = open_source(url, direct);
+    let stream = if input.should_create_async() {
+        input.create_async().await
+    } else {
+        input.create()
+    };
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(AudioStreamError::Fail(why)) => {
+            tracing::debug!("couldn't open {} to measure: {:?}", url, why);
+            return None;
+        }
+        Err(_) => return None,
+    };
+
+    let Some((integrated, peak)) = tokio::task::spawn_blocking(move || measure(stream))
+        .await
+        .ok()
+        .flatten()
+    else {
+        tracing::debug!("couldn't measure {}", url);
+        return None;
+    };
+
+    let loudness = TrackLoudness {
+        url: url.to_string(),
+        integrated,
+        peak,
+    };
+
+    if let Err(why) = database.save_loudness(&loudness).await {
+        tracing::warn!("problem saving loudness of {}: {:?}", url, why);
+    }
+
+    Some(loudness)
+}
+
+/// the gain that brings a track to the target loudness. like replaygain, quiet tracks are only
+/// turned up as far as they can go without clipping
+fn gain_for(loudness: &TrackLoudness, target: i8) -> f32 {
+    let db = (f64::from(target) - loudness.integrated).min(MAX_BOOST);
+    #[allow(clippy::cast_possible_truncation)]
+    let gain = 10f64.powf(db / 20.) as f32;
+
+    if gain > 1. && loudness.peak > 0. {
+        gain.min((1. / loudness.peak).max(1.))
+    } else {
+        gain
+    }
+}
+
+/// the url to measure a track from, and whether it's an audio file. stations and livestreams never
+/// end, so they can't be measured
+async fn measurable(track: &TrackHandle) -> Option<(Url, bool)> {
+    let typemap = track.typemap().read().await;
+
+    if typemap.contains_key::<LiveStream>() || typemap.get::<TrackMetadata>()?.duration.is_none() {
+        return None;
+    }
+
+    let url = typemap.get::<TrackSource>()?.clone();
+    Some((url, typemap.contains_key::<DirectSource>()))
+}
+
+/// turns a track up or down to the guild's target loudness, or back to normal if the guild
+/// doesn't normalize
+async fn apply_gain(
+    track: &TrackHandle,
+    settings: &MusicSettings,
+    loudness: Option<&TrackLoudness>,
+) {
+    let gain = loudness
+        .filter(|_| settings.normalize)
+        .map_or(1., |loudness| gain_for(loudness, settings.target_loudness));
+
+    track.typemap().write().await.insert::<TrackGain>(gain);
+    set_track_volume(track, settings.volume).await;
+}
+
+/// normalizes a track that's starting. one that hasn't been measured yet plays as it is, rather
+/// than jumping in volume partway through, and gets measured for the next time it's played
+pub(super) async fn normalize(guild: GuildId, database: &Arc<Database>, track: &TrackHandle) {
+    let Ok(settings) = database.get_music_settings(&guild).await else {
+        return;
+    };
+
+    let (url, direct) = match measurable(track).await {
+        Some(source) if settings.normalize => source,
+        _ => {
+            apply_gain(track, &settings, None).await;
+            return;
+        }
+    };
+
+    match database.get_loudness(url.as_str()).await {
+        Ok(Some(loudness)) => apply_gain(track, &settings, Some(&loudness)).await,
+        Ok(None) => {
+            apply_gain(track, &settings, None).await;
+            tokio::spawn(measure_later(database.clone(), url, direct));
+        }
+        Err(why) => tracing::warn!("problem loading loudness of {}: {:?}", url, why),
+    }
+}
+
+/// normalizes tracks as they start, and measures the ones coming up so they're ready in time
+pub(super) struct Normalize {
+    pub manager: Arc<Songbird>,
+    pub guild: GuildId,
+    pub database: Arc<Database>,
+}
+
+#[async_trait]
+impl EventHandler for Normalize {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track(list) = ctx else {
+            return None;
+        };
+
+        for (_, track) in *list {
+            normalize(self.guild, &self.database, track).await;
+        }
+
+        if !self
+            .database
+            .get_music_settings(&self.guild)
+            .await
+            .is_ok_and(|settings| settings.normalize)
+        {
+            return None;
+        }
+
+        let upcoming = self
+            .manager
+            .get(self.guild)?
+            .lock()
+            .await
+            .queue()
+            .current_queue();
+        for track in upcoming.iter().skip(1).take(PREFETCH) {
+            if let Some((url, direct)) = measurable(track).await {
+                tokio::spawn(measure_later(self.database.clone(), url, direct));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::LoudnessMeter;
+
+    /// a stereo sine wave at `frequency` with peaks at `amplitude`, interleaved
+    #[allow(clippy::cast_precision_loss)]
+    fn sine(frequency: f32, amplitude: f32, sample_rate: u32, seconds: u32) -> Vec<f32> {
+        (0..sample_rate * seconds)
+            .map(|i| amplitude * (TAU * frequency * i as f32 / sample_rate as f32).sin())
+            .flat_map(|sample| [sample, sample])
+            .collect()
+    }
+
+    fn measure(samples: &[f32], sample_rate: u32) -> Option<f64> {
+        let mut meter = LoudnessMeter::new(sample_rate, 2);
+        meter.push(samples);
+        meter.finish().map(|(loudness, _)| loudness)
+    }
+
+    #[test]
+    fn reference_tone() {
+        // ebu tech 3341: a 1khz stereo sine at -23 dbfs measures -23 LUFS
+        let amplitude = 10f32.powf(-23. / 20.);
+
+        for sample_rate in [44_100, 48_000] {
+            let loudness = measure(&sine(1_000., amplitude, sample_rate, 3), sample_rate)
+                .expect("there's a tone in there");
+            assert!((loudness + 23.).abs() < 0.1, "{sample_rate}: {loudness}");
+        }
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        let amplitude = 10f32.powf(-20. / 20.);
+        let mut samples = sine(1_000., amplitude, 48_000, 5);
+        samples.extend(vec![0.; 48_000 * 2 * 5]);
+
+        let loudness = measure(&samples, 48_000).expect("there's a tone in there");
+        assert!((loudness + 20.).abs() < 0.5, "{loudness}");
+    }
+
+    #[test]
+    fn nothing_to_measure_in_silence() {
+        assert_eq!(measure(&vec![0.; 48_000 * 2 * 2], 48_000), None);
+        // too short for a single block
+        assert_eq!(measure(&sine(1_000., 0.5, 48_000, 0), 48_000), None);
+    }
+
+    #[test]
+    fn tracks_the_peak() {
+        let mut meter = LoudnessMeter::new(48_000, 2);
+        meter.push(&sine(440., 0.5, 48_000, 1));
+
+        let (_, peak) = meter.finish().expect("there's a tone in there");
+        assert!((peak - 0.5).abs() < 0.01, "{peak}");
+    }
+}
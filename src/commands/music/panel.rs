@@ -0,0 +1,517 @@
+use std::sync::Arc;
+
+use poise::{
+    serenity_prelude::{
+        self as serenity, ButtonStyle, ChannelId, ComponentInteraction, CreateActionRow,
+        CreateButton, CreateEmbed, CreateInteractionResponse, CreateInteractionResponseMessage,
+        CreateMessage, EditMessage, GuildId, Http, Interaction, MessageId,
+    },
+    ChoiceParameter,
+};
+use rgb::RGB;
+use songbird::{
+    events::context_data::{DisconnectKind, DisconnectReason},
+    model::CloseCode,
+    tracks::{PlayMode, TrackHandle},
+    Event, EventContext, EventHandler, Songbird,
+};
+
+use crate::{data::MusicSettings, local_get, serenity::async_trait, Data, Error};
+
+use super::{
+    attachments::get_cover, color::get_color_from_thumbnail, get_filter, get_loop_mode,
+    make_now_playing_embed, member_is_dj, vote_skip, LoopMode, MusicSessions, TrackMetadata,
+    TrackRequester,
+};
+
+/// every button on the panel has a custom id starting with this
+const PANEL_PREFIX: &str = "np:";
+const PAUSE_ID: &str = "np:pause";
+const SKIP_ID: &str = "np:skip";
+const LOOP_ID: &str = "np:loop";
+const STOP_ID: &str = "np:stop";
+
+/// the now playing message for a guild's music session. there's only ever one, and it gets
+/// edited as the session goes on
+#[derive(Debug)]
+pub struct Panel {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub color: Option<RGB<u8>>,
+    /// whether the panel shows a progress bar that gets refreshed every so often
+    pub progress: bool,
+}
+
+fn make_panel_buttons(paused: bool, loop_mode: LoopMode) -> Vec<CreateActionRow> {
+    vec![CreateActionRow::Buttons(vec![
+        CreateButton::new(PAUSE_ID)
+            .emoji(if paused { '▶' } else { '⏸' })
+            .label(if paused { "Resume" } else { "Pause" })
+            .style(ButtonStyle::Secondary),
+        CreateButton::new(SKIP_ID)
+            .emoji('⏭')
+            .label("Skip")
+            .style(ButtonStyle::Secondary),
+        CreateButton::new(LOOP_ID)
+            .emoji('🔁')
+            .label(format!("Loop: {}", loop_mode.name()))
+            .style(if loop_mode == LoopMode::Off {
+                ButtonStyle::Secondary
+            } else {
+                ButtonStyle::Primary
+            }),
+        CreateButton::new(STOP_ID)
+            .emoji('⏹')
+            .label("Stop")
+            .style(ButtonStyle::Danger),
+    ])]
+}
+
+/// builds the panel's embed and buttons for a track as it is right now
+async fn render_panel(
+    track: &TrackHandle,
+    sessions: &MusicSessions,
+    guild: GuildId,
+    color: Option<RGB<u8>>,
+    progress: bool,
+) -> (CreateEmbed, Vec<CreateActionRow>) {
+    let state = track.get_info().await.ok();
+    let paused = state
+        .as_ref()
+        .is_some_and(|state| matches!(state.playing, PlayMode::Pause));
+    let position = state.filter(|_| progress).map(|state| state.position);
+    let loop_mode = get_loop_mode(sessions, &guild).await;
+    let filter = get_filter(sessions, &guild).await;
+
+    let typemap = track.typemap().read().await;
+    let metadata = typemap
+        .get::<TrackMetadata>()
+        .expect("tracks should ALWAYS have metadata");
+    let mut embed = make_now_playing_embed(
+        metadata,
+        color,
+        typemap.get::<TrackRequester>(),
+        loop_mode,
+        filter,
+        paused,
+        position,
+    );
+
+    // show_panel attaches the cover to the message, so it stays there through later edits
+    if metadata.thumbnail.is_none() {
+        if let Some(cover) = get_cover(metadata).await {
+            embed = embed.thumbnail(cover.attachment_url());
+        }
+    }
+
+    (embed, make_panel_buttons(paused, loop_mode))
+}
+
+/// shows `track` on the guild's panel. the existing panel is edited if it's in `channel_id`,
+/// otherwise a new one is posted and the old one is deleted
+pub(super) async fn show_panel(
+    http: &Http,
+    channel_id: ChannelId,
+    track: &TrackHandle,
+    sessions: &MusicSessions,
+    guild: GuildId,
+    settings: &MusicSettings,
+) -> Result<(), Error> {
+    let (color, cover) = match track.typemap().read().await.get::<TrackMetadata>() {
+        Some(metadata) if metadata.thumbnail.is_none() => (
+            get_color_from_thumbnail(metadata).await,
+            get_cover(metadata).await,
+        ),
+        Some(metadata) => (get_color_from_thumbnail(metadata).await, None),
+        None => (None, None),
+    };
+    let color = color.or_else(|| settings.default_color());
+    let progress = settings.now_playing_progress;
+    let (embed, components) = render_panel(track, sessions, guild, color, progress).await;
+
+    let existing = sessions
+        .lock()
+        .await
+        .get(&guild)
+        .and_then(|s| s.panel.as_ref())
+        .map(|p| (p.channel_id, p.message_id));
+
+    let message_id = match existing {
+        Some((existing_channel, message_id)) if existing_channel == channel_id => {
+            let edit = EditMessage::new()
+                .embed(embed.clone())
+                .components(components.clone());
+            // the last track's cover has to go either way
+            let edit = match &cover {
+                Some(cover) => edit.new_attachment(cover.attachment()),
+                None => edit.remove_all_attachments(),
+            };
+
+            match channel_id.edit_message(http, message_id, edit).await {
+                Ok(_) => Some(message_id),
+                // probably deleted by someone, so it'll just get replaced
+                Err(why) => {
+                    tracing::debug!("couldn't edit now playing panel: {:?}", why);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let message_id = match message_id {
+        Some(message_id) => message_id,
+        None => {
+            channel_id
+                .send_message(
+                    http,
+                    CreateMessage::new()
+                        .add_embed(embed)
+                        .components(components)
+                        .add_files(cover.map(|cover| cover.attachment())),
+                )
+                .await?
+                .id
+        }
+    };
+
+    let old = sessions
+        .lock()
+        .await
+        .entry(guild)
+        .or_default()
+        .panel
+        .replace(Panel {
+            channel_id,
+            message_id,
+            color,
+            progress,
+        });
+
+    if let Some(old) = old.filter(|old| old.message_id != message_id) {
+        delete_panel(http, &old).await;
+    }
+
+    Ok(())
+}
+
+async fn delete_panel(http: &Http, panel: &Panel) {
+    if let Err(why) = panel
+        .channel_id
+        .delete_message(http, panel.message_id)
+        .await
+    {
+        tracing::debug!("couldn't delete now playing panel: {:?}", why);
+    }
+}
+
+/// edits the guild's panel to match whatever is playing now
+pub(super) async fn refresh_panel(
+    http: &Http,
+    manager: &Songbird,
+    sessions: &MusicSessions,
+    guild: GuildId,
+) {
+    let Some((channel_id, message_id, color, progress)) = sessions
+        .lock()
+        .await
+        .get(&guild)
+        .and_then(|s| s.panel.as_ref())
+        .map(|p| (p.channel_id, p.message_id, p.color, p.progress))
+    else {
+        return;
+    };
+
+    let current = match manager.get(guild) {
+        Some(handler_lock) => handler_lock.lock().await.queue().current(),
+        None => None,
+    };
+
+    let edit = if let Some(track) = current {
+        let (embed, components) = render_panel(&track, sessions, guild, color, progress).await;
+        EditMessage::new().embed(embed).components(components)
+    } else {
+        EditMessage::new().components(vec![])
+    };
+
+    if let Err(why) = channel_id.edit_message(http, message_id, edit).await {
+        tracing::warn!("problem updating now playing panel: {:?}", why);
+    }
+}
+
+/// keeps the progress bar on a guild's panel moving
+pub(super) struct PanelProgress {
+    pub http: Arc<Http>,
+    pub manager: Arc<Songbird>,
+    pub guild: GuildId,
+    pub sessions: Arc<MusicSessions>,
+}
+
+#[async_trait]
+impl EventHandler for PanelProgress {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let progress = self
+            .sessions
+            .lock()
+            .await
+            .get(&self.guild)
+            .and_then(|s| s.panel.as_ref())
+            .is_some_and(|p| p.progress);
+
+        if progress {
+            let current = self
+                .manager
+                .get(self.guild)?
+                .lock()
+                .await
+                .queue()
+                .current()?;
+            // nothing moves while it's paused
+            if current
+                .get_info()
+                .await
+                .is_ok_and(|state| matches!(state.playing, PlayMode::Play))
+            {
+                refresh_panel(&self.http, &self.manager, &self.sessions, self.guild).await;
+            }
+        }
+
+        None
+    }
+}
+
+/// deletes a guild's panel once the bot leaves voice, or gets kicked out of it
+pub(super) struct PanelCleanup {
+    pub http: Arc<Http>,
+    pub guild: GuildId,
+    pub sessions: Arc<MusicSessions>,
+}
+
+#[async_trait]
+impl EventHandler for PanelCleanup {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::DriverDisconnect(data) = ctx else {
+            return None;
+        };
+
+        // anything else is a blip that songbird reconnects from by itself. a failed reconnect
+        // means it gave up
+        let left = matches!(data.kind, DisconnectKind::Reconnect)
+            || matches!(
+                data.reason,
+                Some(
+                    DisconnectReason::Requested
+                        | DisconnectReason::WsClosed(Some(CloseCode::Disconnected))
+                )
+            );
+        if !left {
+            return None;
+        }
+
+        let panel = self
+            .sessions
+            .lock()
+            .await
+            .get_mut(&self.guild)
+            .and_then(|s| s.panel.take());
+
+        if let Some(panel) = panel {
+            delete_panel(&self.http, &panel).await;
+        }
+
+        None
+    }
+}
+
+async fn reply(
+    ctx: &serenity::Context,
+    component: &ComponentInteraction,
+    data: &Data,
+    key: &str,
+) -> Result<(), Error> {
+    component
+        .create_response(
+            ctx,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(local_get(&data.translator, key, &component.locale))
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// handles presses of the buttons on a now playing panel
+pub async fn handle_panel(
+    ctx: &serenity::Context,
+    data: &Data,
+    interaction: &Interaction,
+) -> Result<(), Error> {
+    let Some(component) = interaction.as_message_component() else {
+        return Ok(());
+    };
+    let (Some(guild_id), true) = (
+        component.guild_id,
+        component.data.custom_id.starts_with(PANEL_PREFIX),
+    ) else {
+        return Ok(());
+    };
+    let Some(manager) = songbird::get(ctx).await else {
+        return Ok(());
+    };
+
+    // the same checks as the slash commands: you have to be listening to use the controls
+    let user_channel = ctx.cache.guild(guild_id).and_then(|guild| {
+        guild
+            .voice_states
+            .get(&component.user.id)
+            .and_then(|v| v.channel_id)
+    });
+    let Some(user_channel) = user_channel else {
+        return reply(ctx, component, data, "commands_music_usernotinvc").await;
+    };
+    let Some(handler_lock) = manager.get(guild_id) else {
+        return reply(ctx, component, data, "commands_music_botnotinvc").await;
+    };
+
+    let handler = handler_lock.lock().await;
+    let Some(bot_channel) = handler.current_channel() else {
+        drop(handler);
+        return reply(ctx, component, data, "commands_music_botnotinvc").await;
+    };
+    if bot_channel.0.get() != user_channel.get() {
+        drop(handler);
+        return reply(ctx, component, data, "commands_music_notwithbot").await;
+    }
+    let Some(track) = handler.queue().current() else {
+        drop(handler);
+        return reply(
+            ctx,
+            component,
+            data,
+            "commands_music_controls_skip_notplaying",
+        )
+        .await;
+    };
+
+    match component.data.custom_id.as_str() {
+        PAUSE_ID => {
+            let paused = track
+                .get_info()
+                .await
+                .is_ok_and(|state| matches!(state.playing, PlayMode::Pause));
+            let _ = if paused { track.play() } else { track.pause() };
+        }
+        SKIP_ID => {
+            let listeners = ctx.cache.guild(guild_id).map_or(0, |guild| {
+                guild
+                    .voice_states
+                    .values()
+                    .filter(|v| v.channel_id == Some(user_channel))
+                    .count()
+            });
+            let (skipped, votes, needed) =
+                vote_skip(&handler, &track, component.user.id, listeners).await;
+            drop(handler);
+
+            // a skip posts a new panel for the next track, so there's nothing to update here
+            let content = if skipped {
+                local_get(
+                    &data.translator,
+                    "commands_music_controls_skip_success",
+                    &component.locale,
+                )
+            } else {
+                local_get(
+                    &data.translator,
+                    "commands_music_controls_skip_voted",
+                    &component.locale,
+                )
+                .replace("{votes}", &votes.to_string())
+                .replace("{needed}", &needed.to_string())
+            };
+
+            component
+                .create_response(
+                    ctx,
+                    CreateInteractionResponse::Message(
+                        CreateInteractionResponseMessage::new()
+                            .content(content)
+                            .ephemeral(true),
+                    ),
+                )
+                .await?;
+
+            return Ok(());
+        }
+        LOOP_ID => {
+            let mode = {
+                let mut sessions = data.music_sessions.lock().await;
+                let session = sessions.entry(guild_id).or_default();
+                session.loop_mode = match session.loop_mode {
+                    LoopMode::Off => LoopMode::Track,
+                    LoopMode::Track => LoopMode::Queue,
+                    LoopMode::Queue => LoopMode::Off,
+                };
+                session.loop_mode
+            };
+
+            let _ = if mode == LoopMode::Track {
+                track.enable_loop()
+            } else {
+                track.disable_loop()
+            };
+        }
+        STOP_ID => {
+            let is_dj = match &component.member {
+                Some(member) => member_is_dj(&data.database, guild_id, member).await?,
+                None => false,
+            };
+            if !is_dj {
+                drop(handler);
+                return reply(ctx, component, data, "commands_music_notdj").await;
+            }
+
+            handler.queue().stop();
+            drop(handler);
+
+            component
+                .create_response(
+                    ctx,
+                    CreateInteractionResponse::UpdateMessage(
+                        CreateInteractionResponseMessage::new().components(vec![]),
+                    ),
+                )
+                .await?;
+
+            return Ok(());
+        }
+        _ => {}
+    }
+    drop(handler);
+
+    let (color, progress) = data
+        .music_sessions
+        .lock()
+        .await
+        .get(&guild_id)
+        .and_then(|s| s.panel.as_ref())
+        .map_or((None, false), |p| (p.color, p.progress));
+    let (embed, components) =
+        render_panel(&track, &data.music_sessions, guild_id, color, progress).await;
+
+    component
+        .create_response(
+            ctx,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .embed(embed)
+                    .components(components),
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
@@ -1,21 +1,34 @@
-use std::sync::LazyLock;
+use std::time::Duration;
 
 use poise::{
     send_application_reply,
-    serenity_prelude::{Attachment, Channel, CreateMessage},
+    serenity_prelude::{
+        Attachment, ChannelId, ComponentInteractionCollector, ComponentInteractionDataKind,
+        CreateActionRow, CreateInteractionResponse, CreateInteractionResponseMessage,
+        CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption,
+    },
     CreateReply,
 };
-use reqwest::Client;
-use songbird::input::{Compose, YoutubeDl};
+use serde_derive::Deserialize;
+use songbird::input::{AuxMetadata, Compose, YoutubeDl};
+use tokio::process::Command;
 use url::Url;
 
-use crate::{commands::music::TrackMetadata, local_get, Context, Error, MIME_AUDIO_REGEX};
+use crate::{local_get, Context, Error};
 
 use super::{
-    get_color_from_thumbnail, get_handler, make_now_playing_embed, QuickLeave, TrackRequester,
+    attachments::{read_attachment, read_remote, Unplayable, MAX_ATTACHMENT_MB},
+    enqueue_track, format_duration, get_handler, get_loop_mode, get_requester,
+    limits::apply_limits,
+    panel::show_panel,
+    persist_queue,
+    queue::fair_order,
+    radio::is_live,
+    sources::{classify, SourceKind},
+    volume_to_gain, LoopMode, PendingTrack, HTTP_CLIENT,
 };
 
-#[poise::command(slash_command, subcommands("url", "attachment"))]
+#[poise::command(slash_command, subcommands("url", "search", "attachment"))]
 #[allow(clippy::unused_async)]
 pub async fn play(_: Context<'_>) -> Result<(), Error> {
     Ok(())
@@ -28,6 +41,125 @@ async fn url(ctx: Context<'_>, url: Url, quick_leave: Option<bool>) -> Result<()
     _play_url(ctx, url, quick_leave).await
 }
 
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn search(ctx: Context<'_>, query: String, quick_leave: Option<bool>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locales should always be available for slash commands");
+
+    ctx.defer_ephemeral().await?;
+
+    let results: Vec<_> = YoutubeDl::new_search(HTTP_CLIENT.clone(), query)
+        .search(Some(5))
+        .await?
+        .filter(|r| r.source_url.is_some())
+        .collect();
+
+    if results.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_playback_search_noresults",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    let ctx_id = ctx.id();
+    let select_id = format!("{ctx_id}search");
+
+    let options = results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let title = result.title.as_deref().unwrap_or("-");
+            let details = format!(
+                "{} • {}",
+                result.artist.as_deref().unwrap_or("-"),
+                result
+                    .duration
+                    .map_or_else(|| "?".to_string(), format_duration)
+            );
+
+            CreateSelectMenuOption::new(truncate(title, 100), i.to_string())
+                .description(truncate(&details, 100))
+        })
+        .collect();
+
+    let reply = send_application_reply(
+        ctx,
+        CreateReply::default()
+            .content(local_get(
+                &ctx.data.translator,
+                "commands_music_playback_search_pick",
+                locale,
+            ))
+            .components(vec![CreateActionRow::SelectMenu(CreateSelectMenu::new(
+                &select_id,
+                CreateSelectMenuKind::String { options },
+            ))]),
+    )
+    .await?;
+
+    let Some(press) = ComponentInteractionCollector::new(ctx)
+        .custom_ids(vec![select_id])
+        .author_id(ctx.author().id)
+        .timeout(Duration::from_secs(60))
+        .await
+    else {
+        reply
+            .edit(
+                poise::Context::Application(ctx),
+                CreateReply::default()
+                    .content(local_get(
+                        &ctx.data.translator,
+                        "commands_music_playback_search_timeout",
+                        locale,
+                    ))
+                    .components(vec![]),
+            )
+            .await?;
+
+        return Ok(());
+    };
+
+    let ComponentInteractionDataKind::StringSelect { values } = &press.data.kind else {
+        return Ok(());
+    };
+
+    let Some(result) = values
+        .first()
+        .and_then(|v| v.parse::<usize>().ok())
+        .and_then(|i| results.get(i))
+    else {
+        return Ok(());
+    };
+
+    press
+        .create_response(
+            ctx.serenity_context(),
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(format!("*{}*", result.title.as_deref().unwrap_or("-")))
+                    .components(vec![]),
+            ),
+        )
+        .await?;
+
+    let url = Url::parse(
+        result
+            .source_url
+            .as_deref()
+            .expect("results without urls were filtered out"),
+    )?;
+
+    _play_url(ctx, url, quick_leave).await
+}
+
 #[poise::command(slash_command, ephemeral, guild_only)]
 async fn attachment(
     ctx: Context<'_>,
@@ -37,44 +169,68 @@ async fn attachment(
     let locale = ctx
         .locale()
         .expect("locales should always be available for slash commands");
-    if let Some(content_type) = file.content_type {
-        if MIME_AUDIO_REGEX.is_match(&content_type) {
-            ctx.defer_ephemeral().await?;
-            _play_url(
-                ctx,
-                Url::parse(&file.url).expect("this should be a valid url from discord"),
-                quick_leave,
-            )
-            .await?;
-        } else {
-            send_application_reply(
-                ctx,
-                CreateReply::default().content(local_get(
-                    &ctx.data.translator,
-                    "commands_music_playback_attachment_notaudio",
-                    locale,
-                )),
-            )
-            .await?;
-        }
-    } else {
+
+    ctx.defer_ephemeral().await?;
+
+    if file.size > MAX_ATTACHMENT_MB * 1024 * 1024 {
         send_application_reply(
             ctx,
-            CreateReply::default().content(local_get(
-                &ctx.data.translator,
-                "commands_music_playback_attachment_nocontenttype",
-                locale,
-            )),
+            CreateReply::default()
+                .content(Unplayable::TooLarge.explain(&ctx.data.translator, locale)),
         )
         .await?;
+
+        return Ok(());
     }
 
-    Ok(())
+    let url = Url::parse(&file.url).expect("this should be a valid url from discord");
+    // the content type discord gives us is just a guess from the file name, so the file itself
+    // decides whether it gets played
+    let metadata = match read_attachment(
+        &url,
+        &file.filename,
+        file.content_type.as_deref(),
+        file.download().await?,
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(why) => {
+            send_application_reply(
+                ctx,
+                CreateReply::default().content(why.explain(&ctx.data.translator, locale)),
+            )
+            .await?;
+
+            return Ok(());
+        }
+    };
+
+    // too long files get turned away by the guild's limits, same as any other track
+    _play(
+        ctx,
+        // discord's cdn is happy to stream it to us directly
+        PlayRequest::Tracks(vec![PendingTrack::direct(url, metadata)]),
+        quick_leave,
+    )
+    .await
+}
+
+/// what to queue, either a url that still needs resolving or tracks we already know about
+pub(super) enum PlayRequest {
+    Url(Url),
+    Tracks(Vec<PendingTrack>),
 }
 
 async fn _play_url(ctx: Context<'_>, url: Url, quick_leave: Option<bool>) -> Result<(), Error> {
-    static CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+    _play(ctx, PlayRequest::Url(url), quick_leave).await
+}
 
+pub(super) async fn _play(
+    ctx: Context<'_>,
+    request: PlayRequest,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
     let locale = ctx
         .locale()
         .expect("locales should always be available for slash commands");
@@ -105,9 +261,8 @@ async fn _play_url(ctx: Context<'_>, url: Url, quick_leave: Option<bool>) -> Res
 
     let handler_lock = get_handler(&ctx, &guild_id, &connect_to).await?;
 
-    let mut handler = handler_lock.lock().await;
-
-    if let Some(current_channel) = handler.current_channel() {
+    let current_channel = handler_lock.lock().await.current_channel();
+    if let Some(current_channel) = current_channel {
         if current_channel != connect_to.into() {
             send_application_reply(
                 ctx,
@@ -123,84 +278,308 @@ async fn _play_url(ctx: Context<'_>, url: Url, quick_leave: Option<bool>) -> Res
         }
     };
 
-    let mut source = YoutubeDl::new(CLIENT.clone(), url.into());
+    let settings = ctx.data.database.get_music_settings(&guild_id).await?;
+
+    let tracks = match request {
+        PlayRequest::Url(url) => {
+            let Some(tracks) = resolve_url(ctx, url, settings.max_playlist_tracks).await? else {
+                return Ok(());
+            };
 
-    //  {
-    //     Ok(source) => source,
-    //     Err(why) => {
-    //         println!("problem starting source: {:?}", why);
+            tracks
+        }
+        PlayRequest::Tracks(tracks) => tracks,
+    };
 
-    //         send_application_reply(ctx, |r| {
-    //             r.content(local_get(
-    //                 &ctx.data.translator,
-    //                 "commands_music_playback_ffmpeg",
-    //                 locale,
-    //             ))
-    //         })
-    //         .await?;
+    if tracks.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_playback_playlist_empty",
+                locale,
+            )),
+        )
+        .await?;
 
-    //         return Ok(());
-    //     }
-    // }
+        return Ok(());
+    }
 
-    let metadata = source.aux_metadata().await?;
+    // resolving can take a while, so the call is only locked once there's something to queue
+    let queue = handler_lock.lock().await.queue().current_queue();
+    let limited = apply_limits(&settings, &queue, ctx.author().id, tracks).await;
+    let tracks = limited.accepted;
+    let skipped = limited
+        .reason
+        .map(|reason| reason.explain(&ctx.data.translator, locale));
+
+    if tracks.is_empty() {
+        if let Some(reason) = skipped {
+            send_application_reply(ctx, CreateReply::default().content(reason)).await?;
+        }
 
-    let handle = handler.enqueue(source.into()).await;
+        return Ok(());
+    }
 
-    let (name, avatar_url) = (ctx.author_member().await).map_or_else(
-        || (ctx.author().name.clone(), ctx.author().face()),
-        |member| (member.display_name().to_owned(), member.face()),
-    );
+    let requester = get_requester(&ctx).await;
+    let quick_leave = quick_leave.is_some_and(|q| q);
+    let track_count = tracks.len();
 
-    let mut type_map = handle.typemap().write().await;
-    type_map.insert::<TrackRequester>(TrackRequester { name, avatar_url });
+    // a queue someone has moved tracks around in is left in the order they chose
+    let arranged = ctx
+        .data
+        .music_sessions
+        .lock()
+        .await
+        .get(&guild_id)
+        .is_some_and(|session| session.arranged);
 
-    type_map.insert::<TrackMetadata>(metadata);
-    if quick_leave.is_some_and(|q| q) {
-        type_map.insert::<QuickLeave>(QuickLeave);
+    let mut handler = handler_lock.lock().await;
+    let mut first_handle = None;
+    for track in tracks {
+        let handle = enqueue_track(
+            &mut handler,
+            guild_id,
+            &ctx.data.music_sessions,
+            track,
+            requester.clone(),
+            quick_leave,
+            volume_to_gain(settings.volume),
+        )
+        .await;
+        first_handle.get_or_insert(handle);
     }
+    let handle = first_handle.expect("there's always at least one track to queue");
 
-    drop(type_map);
+    if settings.fair_queue && !arranged {
+        fair_order(&handler).await;
+    }
 
-    send_application_reply(
-        ctx,
-        CreateReply::default().content(local_get(
+    if let Err(why) = persist_queue(&ctx.data.database, guild_id, &handler).await {
+        tracing::warn!("problem saving queue: {:?}", why);
+    }
+
+    // if the queue only has what we just added, the first of it is playing now
+    let playing_now = handler.queue().len() == track_count;
+    let current_channel = handler.current_channel();
+    drop(handler);
+
+    let mut content = if track_count == 1 {
+        local_get(
             &ctx.data.translator,
             "commands_music_playback_queued",
             locale,
-        )),
-    )
-    .await?;
+        )
+    } else {
+        local_get(
+            &ctx.data.translator,
+            "commands_music_playback_queuedmany",
+            locale,
+        )
+        .replace("{count}", &track_count.to_string())
+    };
 
-    if handler.queue().len() == 1 {
-        let http = ctx.serenity_context.http.clone();
-        if let Some(current_channel) = handler.current_channel() {
-            if let Ok(Channel::Guild(current_channel)) =
-                http.get_channel(current_channel.0.into()).await
+    if let Some(reason) = skipped {
+        content.push('\n');
+        content.push_str(
+            &local_get(
+                &ctx.data.translator,
+                "commands_music_limits_skipped",
+                locale,
+            )
+            .replace("{count}", &limited.skipped.to_string())
+            .replace("{reason}", &reason),
+        );
+    }
+
+    send_application_reply(ctx, CreateReply::default().content(content)).await?;
+
+    let loop_mode = get_loop_mode(&ctx.data.music_sessions, &guild_id).await;
+
+    if playing_now {
+        if loop_mode == LoopMode::Track {
+            let _ = handle.enable_loop();
+        }
+
+        let announce_to =
+            current_channel.and_then(|c| settings.announcement_channel(ChannelId::new(c.0.get())));
+
+        if let Some(announce_to) = announce_to {
+            if let Err(why) = show_panel(
+                &ctx.serenity_context().http,
+                announce_to,
+                &handle,
+                &ctx.data.music_sessions,
+                guild_id,
+                &settings,
+            )
+            .await
             {
-                let type_map = handle.typemap().read().await;
-                let metadata = type_map
-                    .get::<TrackMetadata>()
-                    .expect("metadata MUST be available at this point");
-
-                let color = get_color_from_thumbnail(metadata).await;
-
-                if let Err(why) = current_channel
-                    .send_message(
-                        http,
-                        CreateMessage::new().add_embed(make_now_playing_embed(
-                            metadata,
-                            color,
-                            type_map.get::<TrackRequester>(),
-                        )),
-                    )
-                    .await
-                {
-                    tracing::warn!("Error sending now playing message: {:?}", why);
-                }
+                tracing::warn!("Error sending now playing message: {:?}", why);
             }
         }
     }
 
     Ok(())
 }
+
+/// works out what's behind a url: an audio file, a playlist, or a page with a track on it.
+/// replies and returns `None` if it turns out to be a file we can't play
+async fn resolve_url(
+    ctx: Context<'_>,
+    url: Url,
+    max_playlist_tracks: u16,
+) -> Result<Option<Vec<PendingTrack>>, Error> {
+    let locale = ctx
+        .locale()
+        .expect("locales should always be available for slash commands");
+
+    if let SourceKind::Direct(content_type) = classify(&url).await {
+        // audio files get probed ourselves, yt-dlp would only be slower about it
+        return match read_remote(&url, content_type.as_deref()).await {
+            // files that don't know how long they are only play forever if they're stations
+            Ok(metadata) if metadata.duration.is_none() && is_live(&url).await => {
+                Ok(Some(vec![PendingTrack::station(url, metadata)]))
+            }
+            Ok(metadata) => Ok(Some(vec![PendingTrack::direct(url, metadata)])),
+            Err(why) => {
+                send_application_reply(
+                    ctx,
+                    CreateReply::default().content(why.explain(&ctx.data.translator, locale)),
+                )
+                .await?;
+
+                Ok(None)
+            }
+        };
+    }
+
+    if let Some(entries) = expand_playlist(&url, max_playlist_tracks).await {
+        return Ok(Some(entries));
+    }
+
+    let mut source = YoutubeDl::new(HTTP_CLIENT.clone(), url.to_string());
+    let metadata = source.aux_metadata().await?;
+
+    Ok(Some(vec![PendingTrack::new(url, metadata)]))
+}
+
+/// the parts of `yt-dlp --flat-playlist -J` that we care about
+#[derive(Deserialize)]
+struct FlatPlaylist {
+    #[serde(rename = "_type")]
+    kind: Option<String>,
+    #[serde(default)]
+    entries: Vec<FlatPlaylistEntry>,
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylistEntry {
+    url: Option<String>,
+    title: Option<String>,
+    duration: Option<f64>,
+    channel: Option<String>,
+    uploader: Option<String>,
+    #[serde(default)]
+    thumbnails: Vec<FlatPlaylistThumbnail>,
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylistThumbnail {
+    url: String,
+}
+
+impl FlatPlaylistEntry {
+    fn into_track(self) -> Option<PendingTrack> {
+        let url = Url::parse(self.url.as_deref()?).ok()?;
+
+        let metadata = AuxMetadata {
+            title: self.title,
+            artist: self.channel.or(self.uploader),
+            duration: self
+                .duration
+                .filter(|d| d.is_finite() && *d >= 0.)
+                .map(Duration::from_secs_f64),
+            source_url: Some(url.to_string()),
+            // yt-dlp orders thumbnails from worst to best
+            thumbnail: self.thumbnails.into_iter().last().map(|t| t.url),
+            ..Default::default()
+        };
+
+        Some(PendingTrack::new(url, metadata))
+    }
+}
+
+/// a cheap check so that we only ask yt-dlp about urls that could be playlists
+fn looks_like_playlist(url: &Url) -> bool {
+    url.query_pairs().any(|(key, _)| key == "list")
+        || url
+            .path_segments()
+            .is_some_and(|mut s| s.any(|s| matches!(s, "playlist" | "sets" | "album")))
+}
+
+/// if `url` points to a playlist, resolves up to `limit` of its entries without fetching each one.
+/// returns `None` if it isn't a playlist, or if yt-dlp couldn't figure it out
+async fn expand_playlist(url: &Url, limit: u16) -> Option<Vec<PendingTrack>> {
+    if !looks_like_playlist(url) {
+        return None;
+    }
+
+    let output = match Command::new("yt-dlp")
+        .args([
+            "--flat-playlist",
+            "-J",
+            "--playlist-end",
+            &limit.to_string(),
+            url.as_str(),
+        ])
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            tracing::warn!(
+                "yt-dlp couldn't expand playlist: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return None;
+        }
+        Err(why) => {
+            tracing::warn!("problem running yt-dlp: {:?}", why);
+            return None;
+        }
+    };
+
+    let playlist: FlatPlaylist = match serde_json::from_slice(&output.stdout) {
+        Ok(playlist) => playlist,
+        Err(why) => {
+            tracing::warn!("problem parsing yt-dlp output: {:?}", why);
+            return None;
+        }
+    };
+
+    if playlist.kind.as_deref() != Some("playlist") {
+        return None;
+    }
+
+    Some(
+        playlist
+            .entries
+            .into_iter()
+            .filter_map(FlatPlaylistEntry::into_track)
+            .take(limit.into())
+            .collect(),
+    )
+}
+
+/// truncates a string to at most `max` characters, for discord's component length limits
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() > max {
+        s.chars()
+            .take(max - 1)
+            .chain(std::iter::once('…'))
+            .collect()
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,333 @@
+use std::time::Duration;
+
+use itertools::Itertools;
+use poise::{
+    send_application_reply,
+    serenity_prelude::{CreateEmbed, CreateEmbedFooter, Mentionable},
+    CreateReply,
+};
+
+use crate::{
+    commands::music::{
+        format_duration, get_user_call, is_dj,
+        library::{display_url, is_linkable},
+        playback::{_play, PlayRequest},
+        PendingTrack,
+    },
+    data::SavedPlaylist,
+    local_get, Context, Error,
+};
+
+/// how many tracks `/music playlist show` lists before cutting off
+const SHOW_LIMIT: usize = 20;
+
+#[poise::command(slash_command, subcommands("save", "load", "list", "show", "delete"))]
+#[allow(clippy::unused_async)]
+pub async fn playlist(_: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+async fn autocomplete_playlist(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return vec![];
+    };
+
+    let partial = partial.to_lowercase();
+
+    ctx.data
+        .database
+        .get_playlists(&guild_id)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|p| p.name)
+        .filter(|name| name.to_lowercase().contains(&partial))
+        .take(25)
+        .collect()
+}
+
+/// saves everything in the queue as a playlist
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn save(ctx: Context<'_>, #[max_length = 100] name: String) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let queue = handler_lock.lock().await.queue().current_queue();
+
+    let mut tracks = Vec::with_capacity(queue.len());
+    for track in &queue {
+        let typemap = track.typemap().read().await;
+        if let Some(pending) = PendingTrack::from_typemap(&typemap) {
+            tracks.push(pending.to_saved());
+        }
+    }
+
+    if tracks.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_queue_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    if let Some(existing) = ctx.data.database.get_playlist(&guild_id, &name).await? {
+        if existing.owner_id != ctx.author().id {
+            send_application_reply(
+                ctx,
+                CreateReply::default().content(local_get(
+                    &ctx.data.translator,
+                    "commands_music_playlist_save_taken",
+                    locale,
+                )),
+            )
+            .await?;
+
+            return Ok(());
+        }
+    }
+
+    let track_count = tracks.len();
+    ctx.data
+        .database
+        .save_playlist(&SavedPlaylist {
+            guild_id,
+            name: name.clone(),
+            owner_id: ctx.author().id,
+            tracks,
+        })
+        .await?;
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(
+                &ctx.data.translator,
+                "commands_music_playlist_save_success",
+                locale,
+            )
+            .replace("{count}", &track_count.to_string())
+            .replace("{name}", &name),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// queues every track in a saved playlist
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn load(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_playlist"] name: String,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let Some(playlist) = ctx.data.database.get_playlist(&guild_id, &name).await? else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_playlist_notfound",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let tracks = playlist
+        .tracks
+        .iter()
+        .filter_map(PendingTrack::from_saved)
+        .collect();
+
+    _play(ctx, PlayRequest::Tracks(tracks), quick_leave).await
+}
+
+/// lists this server's saved playlists
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn list(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let playlists = ctx.data.database.get_playlists(&guild_id).await?;
+
+    if playlists.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_playlist_list_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().embed(
+            CreateEmbed::new().title("Playlists:").description(
+                playlists
+                    .iter()
+                    .map(|p| {
+                        format!(
+                            "**{}** - {} tracks, saved by {}",
+                            p.name,
+                            p.tracks.len(),
+                            p.owner_id.mention()
+                        )
+                    })
+                    .join("\n"),
+            ),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// shows the tracks in a saved playlist
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn show(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_playlist"] name: String,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let Some(playlist) = ctx.data.database.get_playlist(&guild_id, &name).await? else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_playlist_notfound",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let mut description = playlist
+        .tracks
+        .iter()
+        .take(SHOW_LIMIT)
+        .enumerate()
+        .map(|(i, track)| {
+            let title = track
+                .title
+                .clone()
+                .unwrap_or_else(|| display_url(&track.url));
+            let title = if is_linkable(&track.url) {
+                format!("[{title}]({})", track.url)
+            } else {
+                title
+            };
+
+            format!(
+                "**{}.** {title} `{}`",
+                i + 1,
+                track.duration_ms.map_or_else(
+                    || "?".to_string(),
+                    |d| format_duration(Duration::from_millis(d))
+                )
+            )
+        })
+        .join("\n");
+
+    if playlist.tracks.len() > SHOW_LIMIT {
+        description.push_str(&format!(
+            "\n*...and {} more*",
+            playlist.tracks.len() - SHOW_LIMIT
+        ));
+    }
+
+    let total = playlist
+        .tracks
+        .iter()
+        .filter_map(|t| t.duration_ms)
+        .map(Duration::from_millis)
+        .sum::<Duration>();
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().embed(
+            CreateEmbed::new()
+                .title(format!("{}:", playlist.name))
+                .description(description)
+                .footer(CreateEmbedFooter::new(format!(
+                    "{} tracks • {}",
+                    playlist.tracks.len(),
+                    format_duration(total)
+                ))),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// deletes a saved playlist. only the person who saved it (or a DJ) can do this
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn delete(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_playlist"] name: String,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let is_dj = is_dj(&ctx).await?;
+    let key = match ctx.data.database.get_playlist(&guild_id, &name).await? {
+        None => "commands_music_playlist_notfound",
+        Some(playlist) if playlist.owner_id != ctx.author().id && !is_dj => {
+            "commands_music_playlist_delete_notyours"
+        }
+        Some(_) => {
+            ctx.data.database.delete_playlist(&guild_id, &name).await?;
+            "commands_music_playlist_delete_success"
+        }
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::Duration,
+};
+
+use itertools::Itertools;
+use poise::{
+    send_application_reply,
+    serenity_prelude::{CreateEmbed, CreateEmbedFooter},
+    CreateReply,
+};
+use rand::seq::SliceRandom;
+use songbird::{
+    tracks::{Queued, TrackHandle},
+    Call,
+};
+
+use crate::{
+    commands::music::{
+        dj_check, format_duration, get_user_call, is_dj,
+        library::{display_url, is_linkable},
+        send_paginated, TrackMetadata, TrackRequester,
+    },
+    local_get, Context, Error,
+};
+
+const PAGE_SIZE: usize = 10;
+
+/// puts everyone's upcoming tracks in turns, so one person queueing a whole album doesn't hold up
+/// everybody else. whoever requested the current track has just had their turn, so they go last
+pub(super) async fn fair_order(call: &Call) {
+    let tracks = call.queue().current_queue();
+    if tracks.len() < 3 {
+        return;
+    }
+
+    let mut requesters = HashMap::with_capacity(tracks.len());
+    for track in &tracks {
+        let requester = track
+            .typemap()
+            .read()
+            .await
+            .get::<TrackRequester>()
+            .map(|r| r.id);
+        requesters.insert(track.uuid(), requester);
+    }
+    let current = requesters.get(&tracks[0].uuid()).copied();
+
+    call.queue().modify_queue(|vq| {
+        let upcoming: Vec<Queued> = vq.drain(1..).collect();
+        vq.extend(take_turns(upcoming, current, |track| {
+            requesters.get(&track.uuid()).copied().flatten()
+        }));
+    });
+}
+
+/// interleaves `items` so each key gets one at a time, keeping each key's items in order. keys
+/// take turns in the order they first show up, apart from `last`, which goes at the end
+fn take_turns<T, K: Copy + Eq + Hash>(
+    items: Vec<T>,
+    last: Option<K>,
+    key: impl Fn(&T) -> K,
+) -> Vec<T> {
+    let count = items.len();
+    let mut keys = vec![];
+    let mut turns: HashMap<K, VecDeque<T>> = HashMap::new();
+    for item in items {
+        let k = key(&item);
+        turns
+            .entry(k)
+            .or_insert_with(|| {
+                keys.push(k);
+                VecDeque::new()
+            })
+            .push_back(item);
+    }
+
+    if let Some(position) = last.and_then(|last| keys.iter().position(|k| *k == last)) {
+        let k = keys.remove(position);
+        keys.push(k);
+    }
+
+    let mut ordered = Vec::with_capacity(count);
+    while ordered.len() < count {
+        for k in &keys {
+            if let Some(item) = turns.get_mut(k).and_then(VecDeque::pop_front) {
+                ordered.push(item);
+            }
+        }
+    }
+
+    ordered
+}
+
+struct QueueEntry {
+    title: String,
+    source_url: Option<String>,
+    duration: Option<Duration>,
+    requester: Option<String>,
+}
+
+impl QueueEntry {
+    async fn from_handle(handle: &TrackHandle) -> Self {
+        let typemap = handle.typemap().read().await;
+        let metadata = typemap
+            .get::<TrackMetadata>()
+            .expect("tracks must ALWAYS have metadata");
+
+        Self {
+            title: metadata
+                .title
+                .clone()
+                .or_else(|| metadata.source_url.as_deref().map(display_url))
+                .unwrap_or_else(|| "-".to_string()),
+            source_url: metadata.source_url.clone().filter(|url| is_linkable(url)),
+            duration: metadata.duration,
+            requester: typemap.get::<TrackRequester>().map(|r| r.name.clone()),
+        }
+    }
+
+    fn line(&self, position: usize) -> String {
+        let title = self.source_url.as_ref().map_or_else(
+            || self.title.clone(),
+            |url| format!("[{}]({url})", self.title),
+        );
+        let duration = self
+            .duration
+            .map_or_else(|| "?".to_string(), format_duration);
+        let requester = self.requester.as_deref().unwrap_or("-");
+        let position = if position == 0 {
+            "Now:".to_string()
+        } else {
+            format!("{position}.")
+        };
+
+        format!("**{position}** {title} `{duration}` - {requester}")
+    }
+}
+
+#[poise::command(
+    slash_command,
+    subcommands("view", "remove", "move_track", "shuffle", "clear_mine")
+)]
+#[allow(clippy::unused_async)]
+pub async fn queue(_: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn view(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let handler = handler_lock.lock().await;
+    let tracks = handler.queue().current_queue();
+    drop(handler);
+
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+    let fair = ctx
+        .data
+        .database
+        .get_music_settings(&guild_id)
+        .await?
+        .fair_queue;
+
+    if tracks.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_queue_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    let mut entries = Vec::with_capacity(tracks.len());
+    for track in &tracks {
+        entries.push(QueueEntry::from_handle(track).await);
+    }
+
+    // the current track has already been partially played, so only count what's left of it
+    let elapsed = match tracks[0].get_info().await {
+        Ok(state) => state.position,
+        Err(_) => Duration::ZERO,
+    };
+    let remaining = entries
+        .iter()
+        .filter_map(|e| e.duration)
+        .sum::<Duration>()
+        .saturating_sub(elapsed);
+
+    send_paginated(&ctx, entries.len().div_ceil(PAGE_SIZE), |page| {
+        make_queue_embed(&entries, page, remaining, fair)
+    })
+    .await
+}
+
+fn make_queue_embed(
+    entries: &[QueueEntry],
+    page: usize,
+    remaining: Duration,
+    fair: bool,
+) -> CreateEmbed {
+    let page_count = entries.len().div_ceil(PAGE_SIZE);
+    // the queue is kept in turns already, so this is just so nobody wonders why it isn't in order
+    let mode = if fair { " • taking turns" } else { "" };
+
+    CreateEmbed::new()
+        .title("Queue:")
+        .description(
+            entries
+                .iter()
+                .enumerate()
+                .skip(page * PAGE_SIZE)
+                .take(PAGE_SIZE)
+                .map(|(position, entry)| entry.line(position))
+                .join("\n"),
+        )
+        .footer(CreateEmbedFooter::new(format!(
+            "Page {}/{page_count} • {} tracks • {} remaining{mode}",
+            page + 1,
+            entries.len(),
+            format_duration(remaining)
+        )))
+}
+
+/// removes a track from the queue. position 1 is the next track to play
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn remove(ctx: Context<'_>, #[min = 1] position: usize) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let handler = handler_lock.lock().await;
+
+    let Some(track) = handler
+        .queue()
+        .modify_queue(|vq| vq.get(position).map(Queued::handle))
+    else {
+        drop(handler);
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_queue_invalidposition",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    let is_own_track = track
+        .typemap()
+        .read()
+        .await
+        .get::<TrackRequester>()
+        .is_some_and(|r| r.id == ctx.author().id);
+
+    if !is_own_track && !is_dj(&ctx).await? {
+        drop(handler);
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_queue_remove_notyours",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    // the queue might have moved on while we were checking the requester
+    let removed = handler.queue().modify_queue(|vq| {
+        if vq.get(position).is_some_and(|t| t.uuid() == track.uuid()) {
+            vq.remove(position)
+        } else {
+            None
+        }
+    });
+    drop(handler);
+
+    let key = if let Some(removed) = removed {
+        let _ = removed.stop();
+        "commands_music_queue_remove_success"
+    } else {
+        "commands_music_queue_invalidposition"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// moves a track to a different position in the queue
+#[poise::command(
+    slash_command,
+    ephemeral,
+    guild_only,
+    rename = "move",
+    check = "dj_check"
+)]
+async fn move_track(
+    ctx: Context<'_>,
+    #[min = 1] from: usize,
+    #[min = 1] to: usize,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let handler = handler_lock.lock().await;
+
+    // the current track can't be moved, it's already playing
+    let moved = handler.queue().modify_queue(|vq| {
+        if from == 0 || to == 0 || from >= vq.len() || to >= vq.len() {
+            return false;
+        }
+
+        let track = vq.remove(from).expect("index was just bounds checked");
+        vq.insert(to, track);
+        true
+    });
+    drop(handler);
+
+    if moved {
+        let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+        ctx.data
+            .music_sessions
+            .lock()
+            .await
+            .entry(guild_id)
+            .or_default()
+            .arranged = true;
+    }
+
+    let key = if moved {
+        "commands_music_queue_move_success"
+    } else {
+        "commands_music_queue_invalidposition"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// shuffles every upcoming track in the queue
+#[poise::command(slash_command, ephemeral, guild_only, check = "dj_check")]
+async fn shuffle(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+    let settings = ctx.data.database.get_music_settings(&guild_id).await?;
+
+    // whatever order the queue was put in by hand is gone now
+    ctx.data
+        .music_sessions
+        .lock()
+        .await
+        .entry(guild_id)
+        .or_default()
+        .arranged = false;
+
+    let handler = handler_lock.lock().await;
+    handler.queue().modify_queue(|vq| {
+        if vq.len() > 2 {
+            vq.make_contiguous()[1..].shuffle(&mut rand::thread_rng());
+        }
+    });
+
+    // everyone's tracks get shuffled, but they still take turns
+    if settings.fair_queue {
+        fair_order(&handler).await;
+    }
+    drop(handler);
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(
+            &ctx.data.translator,
+            "commands_music_queue_shuffle_success",
+            locale,
+        )),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// removes all of your upcoming tracks from the queue
+#[poise::command(slash_command, ephemeral, guild_only, rename = "clear-mine")]
+async fn clear_mine(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+
+    let Some(handler_lock) = get_user_call(&ctx).await? else {
+        return Ok(());
+    };
+
+    let handler = handler_lock.lock().await;
+
+    let mut mine = vec![];
+    for track in handler.queue().current_queue().iter().skip(1) {
+        if track
+            .typemap()
+            .read()
+            .await
+            .get::<TrackRequester>()
+            .is_some_and(|r| r.id == ctx.author().id)
+        {
+            mine.push(track.uuid());
+        }
+    }
+
+    let removed = handler.queue().modify_queue(|vq| {
+        let mut removed = vec![];
+        let mut i = 1;
+        while i < vq.len() {
+            if mine.contains(&vq[i].uuid()) {
+                removed.extend(vq.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    });
+    drop(handler);
+
+    for track in &removed {
+        let _ = track.stop();
+    }
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(
+            local_get(
+                &ctx.data.translator,
+                "commands_music_queue_clearmine_success",
+                locale,
+            )
+            .replace("{count}", &removed.len().to_string()),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::take_turns;
+
+    fn turns(items: &[(char, u8)], last: Option<char>) -> Vec<(char, u8)> {
+        take_turns(items.to_vec(), last, |(who, _)| *who)
+    }
+
+    #[test]
+    fn everyone_gets_a_turn() {
+        let queue = [('a', 1), ('a', 2), ('a', 3), ('b', 1), ('c', 1), ('b', 2)];
+
+        assert_eq!(
+            turns(&queue, None),
+            [('a', 1), ('b', 1), ('c', 1), ('a', 2), ('b', 2), ('a', 3)]
+        );
+    }
+
+    #[test]
+    fn whoever_just_played_goes_last() {
+        let queue = [('a', 1), ('a', 2), ('b', 1)];
+
+        assert_eq!(turns(&queue, Some('a')), [('b', 1), ('a', 1), ('a', 2)]);
+    }
+
+    #[test]
+    fn ordering_again_changes_nothing() {
+        let queue = [('a', 1), ('a', 2), ('b', 1), ('c', 1), ('b', 2)];
+        let once = turns(&queue, None);
+
+        assert_eq!(turns(&once, None), once);
+    }
+
+    #[test]
+    fn one_person_keeps_their_order() {
+        let queue = [('a', 3), ('a', 1), ('a', 2)];
+
+        assert_eq!(turns(&queue, Some('b')), queue);
+        assert!(turns(&[], None).is_empty());
+    }
+}
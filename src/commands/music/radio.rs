@@ -0,0 +1,683 @@
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    sync::Arc,
+    time::Duration,
+};
+
+use itertools::Itertools;
+use poise::{
+    send_application_reply,
+    serenity_prelude::{prelude::TypeMapKey, CreateEmbed, GuildId, Http},
+    CreateReply,
+};
+use reqwest::{header::CONTENT_TYPE, Response};
+use songbird::{
+    input::{AudioStream, AudioStreamError, AuxMetadata, Compose},
+    tracks::TrackHandle,
+    Event, EventContext, EventHandler, Songbird,
+};
+use symphonia::core::{io::MediaSource, probe::Hint};
+use tokio::sync::{mpsc, watch};
+use url::Url;
+
+use crate::{
+    commands::music::{
+        dj_check,
+        panel::refresh_panel,
+        playback::{_play, PlayRequest},
+        MusicSessions, PendingTrack, TrackMetadata, HTTP_CLIENT,
+    },
+    data::RadioStation,
+    local_get,
+    serenity::async_trait,
+    Context, Error, MIME_AUDIO_REGEX,
+};
+
+/// how long a station gets to start answering before we give up on it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many chunks of audio can be downloaded ahead of what's been played
+const CHUNK_BUFFER: usize = 64;
+
+/// the titles a radio station sends as it plays. the panel picks these up as they change
+pub(super) struct LiveStream;
+
+impl TypeMapKey for LiveStream {
+    type Value = watch::Receiver<Option<String>>;
+}
+
+/// an internet radio station. icecast and shoutcast servers mix the title of whatever's playing
+/// into the audio when asked to, so those get pulled back out here
+pub(super) struct RadioInput {
+    url: Url,
+    titles: Arc<watch::Sender<Option<String>>>,
+}
+
+impl RadioInput {
+    pub fn new(url: Url) -> (Self, watch::Receiver<Option<String>>) {
+        let (titles, receiver) = watch::channel(None);
+
+        (
+            Self {
+                url,
+                titles: Arc::new(titles),
+            },
+            receiver,
+        )
+    }
+}
+
+#[async_trait]
+impl Compose for RadioInput {
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+
+    async fn create_async(
+        &mut self,
+    ) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        let response = HTTP_CLIENT
+            .get(self.url.as_str())
+            .header("Icy-MetaData", "1")
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(|why| AudioStreamError::Fail(Box::new(why)))?;
+
+        let interval = header(&response, "icy-metaint")
+            .and_then(|interval| interval.parse().ok())
+            .filter(|interval| *interval > 0);
+
+        let mut hint = Hint::new();
+        if let Some(content_type) = header(&response, CONTENT_TYPE.as_str()) {
+            hint.mime_type(content_type);
+        }
+
+        let (audio, chunks) = mpsc::channel(CHUNK_BUFFER);
+        tokio::spawn(pump(
+            response,
+            IcyDemuxer::new(interval),
+            audio,
+            self.titles.clone(),
+        ));
+
+        Ok(AudioStream {
+            input: Box::new(StreamReader {
+                chunks,
+                current: vec![],
+                position: 0,
+            }),
+            hint: Some(hint),
+        })
+    }
+
+    fn should_create_async(&self) -> bool {
+        true
+    }
+
+    async fn aux_metadata(&mut self) -> Result<AuxMetadata, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// downloads a station for as long as it's being played, splitting the titles out of the audio
+async fn pump(
+    mut response: Response,
+    mut demuxer: IcyDemuxer,
+    audio: mpsc::Sender<Vec<u8>>,
+    titles: Arc<watch::Sender<Option<String>>>,
+) {
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(why) => {
+                tracing::warn!("problem reading radio stream: {:?}", why);
+                break;
+            }
+        };
+
+        let mut data = Vec::with_capacity(chunk.len());
+        if let Some(title) = demuxer.push(&chunk, &mut data) {
+            titles.send_if_modified(|current| {
+                let changed = current.as_ref() != Some(&title);
+                *current = Some(title);
+                changed
+            });
+        }
+
+        // the reader is dropped once the track stops, and there's no point downloading after that
+        if !data.is_empty() && audio.send(data).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// where in the stream the demuxer is
+enum DemuxState {
+    /// this much audio is left before the next metadata block
+    Audio(usize),
+    /// the next byte is the length of a metadata block, in 16 byte units
+    Length,
+    /// the rest of a metadata block
+    Metadata { remaining: usize, block: Vec<u8> },
+}
+
+/// splits a stream into audio and the metadata blocks that come every `interval` bytes of it
+struct IcyDemuxer {
+    /// how much audio comes between metadata blocks, if the station sends any
+    interval: Option<usize>,
+    state: DemuxState,
+}
+
+impl IcyDemuxer {
+    fn new(interval: Option<usize>) -> Self {
+        Self {
+            interval,
+            state: DemuxState::Audio(interval.unwrap_or_default()),
+        }
+    }
+
+    /// adds the audio in `chunk` to `audio`, returning the newest title in it if there was one
+    fn push(&mut self, mut chunk: &[u8], audio: &mut Vec<u8>) -> Option<String> {
+        let Some(interval) = self.interval else {
+            audio.extend_from_slice(chunk);
+            return None;
+        };
+
+        let mut title = None;
+        while let Some((&first, rest)) = chunk.split_first() {
+            match &mut self.state {
+                DemuxState::Audio(remaining) => {
+                    let (data, rest) = chunk.split_at((*remaining).min(chunk.len()));
+                    audio.extend_from_slice(data);
+                    *remaining -= data.len();
+                    chunk = rest;
+
+                    if *remaining == 0 {
+                        self.state = DemuxState::Length;
+                    }
+                }
+                DemuxState::Length => {
+                    let length = usize::from(first) * 16;
+                    chunk = rest;
+
+                    self.state = if length == 0 {
+                        DemuxState::Audio(interval)
+                    } else {
+                        DemuxState::Metadata {
+                            remaining: length,
+                            block: Vec::with_capacity(length),
+                        }
+                    };
+                }
+                DemuxState::Metadata { remaining, block } => {
+                    let (data, rest) = chunk.split_at((*remaining).min(chunk.len()));
+                    block.extend_from_slice(data);
+                    *remaining -= data.len();
+                    chunk = rest;
+
+                    if *remaining == 0 {
+                        if let Some(found) = parse_stream_title(block) {
+                            title = Some(found);
+                        }
+                        self.state = DemuxState::Audio(interval);
+                    }
+                }
+            }
+        }
+
+        title
+    }
+}
+
+/// pulls the title out of a metadata block, which looks like `StreamTitle='...';StreamUrl='...';`
+/// padded out with nulls. stations that are between songs send an empty one, which is ignored
+fn parse_stream_title(block: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(block);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    // titles can have quotes in them, so only a quote followed by a semicolon ends one
+    let end = text[start..]
+        .find("';")
+        .map_or_else(|| text.trim_end_matches('\0').len(), |end| start + end);
+
+    let title = text.get(start..end)?.trim();
+
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// the audio half of a station, read out on songbird's side as the pump downloads it
+struct StreamReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.current.len() {
+            // songbird reads from its own threads, so blocking here is fine
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.current = chunk;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.current.len() - self.position);
+        buf[..count].copy_from_slice(&self.current[self.position..self.position + count]);
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "radio stations can't be seeked",
+        ))
+    }
+}
+
+impl MediaSource for StreamReader {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// puts the latest title a station sent into its track's metadata. returns whether it changed
+async fn update_title(track: &TrackHandle) -> bool {
+    let mut typemap = track.typemap().write().await;
+
+    let Some(titles) = typemap.get_mut::<LiveStream>() else {
+        return false;
+    };
+    if !titles.has_changed().unwrap_or(false) {
+        return false;
+    }
+    let Some(title) = titles.borrow_and_update().clone() else {
+        return false;
+    };
+
+    let Some(metadata) = typemap.get_mut::<TrackMetadata>() else {
+        return false;
+    };
+
+    // most stations send `Artist - Title`. the station's name (kept in the album) stands in for
+    // the artist when they don't
+    match title.split_once(" - ") {
+        Some((artist, song)) if !artist.trim().is_empty() && !song.trim().is_empty() => {
+            metadata.artist = Some(artist.trim().to_string());
+            metadata.title = Some(song.trim().to_string());
+        }
+        _ => {
+            metadata.artist.clone_from(&metadata.album);
+            metadata.title = Some(title);
+        }
+    }
+
+    true
+}
+
+/// keeps the panel showing whatever a station is playing right now
+pub(super) struct StreamTitles {
+    pub http: Arc<Http>,
+    pub manager: Arc<Songbird>,
+    pub guild: GuildId,
+    pub sessions: Arc<MusicSessions>,
+}
+
+#[async_trait]
+impl EventHandler for StreamTitles {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let handler_lock = self.manager.get(self.guild)?;
+        let current = handler_lock.lock().await.queue().current();
+
+        if update_title(&current?).await {
+            refresh_panel(&self.http, &self.manager, &self.sessions, self.guild).await;
+        }
+
+        None
+    }
+}
+
+/// whether an audio file without a duration is really a station: it says so in its icy headers,
+/// or the server can't say how long it is because it never ends
+pub(super) async fn is_live(url: &Url) -> bool {
+    let Ok(response) = HTTP_CLIENT
+        .get(url.as_str())
+        .header("Icy-MetaData", "1")
+        .timeout(CONNECT_TIMEOUT)
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+    else {
+        return false;
+    };
+
+    header(&response, "icy-metaint").is_some()
+        || header(&response, "icy-name").is_some()
+        || response.content_length().is_none()
+}
+
+/// connects to a station to make sure it's really streaming audio, and gets its name
+async fn tune_in(url: &Url) -> Option<AuxMetadata> {
+    let response = HTTP_CLIENT
+        .get(url.as_str())
+        .header("Icy-MetaData", "1")
+        .timeout(CONNECT_TIMEOUT)
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .ok()?;
+
+    let is_audio = header(&response, CONTENT_TYPE.as_str())
+        .is_some_and(|content_type| MIME_AUDIO_REGEX.is_match(content_type));
+    if !is_audio && header(&response, "icy-metaint").is_none() {
+        return None;
+    }
+
+    let name = header(&response, "icy-name")
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(ToString::to_string);
+
+    // no duration, so the guild's limits treat it as the livestream it is
+    Some(AuxMetadata {
+        title: name
+            .clone()
+            .or_else(|| url.host_str().map(ToString::to_string)),
+        album: name,
+        source_url: Some(url.to_string()),
+        ..AuxMetadata::default()
+    })
+}
+
+/// parses a station's url, as long as it's one we can connect to
+fn parse_station_url(url: &str) -> Option<Url> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+#[poise::command(slash_command, subcommands("play", "save", "list", "delete"))]
+#[allow(clippy::unused_async)]
+pub async fn radio(_: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+async fn autocomplete_station(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return vec![];
+    };
+
+    let partial = partial.to_lowercase();
+
+    ctx.data
+        .database
+        .get_stations(&guild_id)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| s.name)
+        .filter(|name| name.to_lowercase().contains(&partial))
+        .take(25)
+        .collect()
+}
+
+/// plays an internet radio station, from a link or one saved on this server
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn play(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_station"] station: String,
+    quick_leave: Option<bool>,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let url = match parse_station_url(&station) {
+        Some(url) => url,
+        None => match ctx.data.database.get_station(&guild_id, &station).await? {
+            Some(saved) => Url::parse(&saved.url)?,
+            None => {
+                send_application_reply(
+                    ctx,
+                    CreateReply::default().content(local_get(
+                        &ctx.data.translator,
+                        "commands_music_radio_notfound",
+                        locale,
+                    )),
+                )
+                .await?;
+
+                return Ok(());
+            }
+        },
+    };
+
+    let Some(metadata) = tune_in(&url).await else {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_radio_notastation",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    };
+
+    _play(
+        ctx,
+        PlayRequest::Tracks(vec![PendingTrack::station(url, metadata)]),
+        quick_leave,
+    )
+    .await
+}
+
+/// saves a station under a name, so it can be played without the link
+#[poise::command(slash_command, ephemeral, guild_only, check = "dj_check")]
+async fn save(
+    ctx: Context<'_>,
+    #[max_length = 100] name: String,
+    url: String,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let station = match parse_station_url(&url) {
+        Some(url) => tune_in(&url).await.map(|_| url),
+        None => None,
+    };
+
+    let key = if let Some(url) = station {
+        ctx.data
+            .database
+            .save_station(&RadioStation {
+                guild_id,
+                name,
+                url: url.to_string(),
+            })
+            .await?;
+        "commands_music_radio_save_success"
+    } else {
+        "commands_music_radio_notastation"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// lists this server's saved stations
+#[poise::command(slash_command, ephemeral, guild_only)]
+async fn list(ctx: Context<'_>) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let stations = ctx.data.database.get_stations(&guild_id).await?;
+
+    if stations.is_empty() {
+        send_application_reply(
+            ctx,
+            CreateReply::default().content(local_get(
+                &ctx.data.translator,
+                "commands_music_radio_list_empty",
+                locale,
+            )),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().embed(
+            CreateEmbed::new().title("Stations:").description(
+                stations
+                    .iter()
+                    .map(|s| format!("**{}** - {}", s.name, s.url))
+                    .join("\n"),
+            ),
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// deletes a saved station
+#[poise::command(slash_command, ephemeral, guild_only, check = "dj_check")]
+async fn delete(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_station"] name: String,
+) -> Result<(), Error> {
+    let locale = ctx
+        .locale()
+        .expect("locale should always be available for slash commands");
+    let guild_id = ctx.guild_id().expect("no guild for a guild only command?");
+
+    ctx.defer_ephemeral().await?;
+
+    let deleted = ctx.data.database.delete_station(&guild_id, &name).await?;
+    let key = if deleted.deleted_count == 0 {
+        "commands_music_radio_notfound"
+    } else {
+        "commands_music_radio_delete_success"
+    };
+
+    send_application_reply(
+        ctx,
+        CreateReply::default().content(local_get(&ctx.data.translator, key, locale)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_stream_title, IcyDemuxer};
+
+    /// a metadata block as a station would send it, length byte and padding included
+    fn block(text: &str) -> Vec<u8> {
+        let length = text.len().div_ceil(16);
+        let mut block = vec![u8::try_from(length).expect("short enough for one block")];
+        block.extend_from_slice(text.as_bytes());
+        block.resize(length * 16 + 1, 0);
+        block
+    }
+
+    #[test]
+    fn parses_titles() {
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='Artist - Song';StreamUrl='';\0\0"),
+            Some("Artist - Song".to_string())
+        );
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='Don't Stop';\0"),
+            Some("Don't Stop".to_string())
+        );
+        assert_eq!(parse_stream_title(b"StreamTitle='';\0\0\0"), None);
+        assert_eq!(parse_stream_title(b"StreamUrl='http://example.com';"), None);
+    }
+
+    #[test]
+    fn splits_audio_from_metadata() {
+        let mut stream = vec![1; 8];
+        stream.extend(block("StreamTitle='First';"));
+        stream.extend([2; 8]);
+        stream.push(0);
+        stream.extend([3; 8]);
+        stream.extend(block("StreamTitle='Second';"));
+        stream.extend([4; 3]);
+
+        let mut demuxer = IcyDemuxer::new(Some(8));
+        let mut audio = vec![];
+        let title = demuxer.push(&stream, &mut audio);
+
+        assert_eq!(title, Some("Second".to_string()));
+        assert_eq!(audio.len(), 27);
+        assert!(audio[..8].iter().all(|b| *b == 1));
+        assert!(audio[24..].iter().all(|b| *b == 4));
+    }
+
+    #[test]
+    fn handles_blocks_split_across_chunks() {
+        let mut stream = vec![1; 4];
+        stream.extend(block("StreamTitle='Split';"));
+        stream.extend([2; 4]);
+
+        let mut demuxer = IcyDemuxer::new(Some(4));
+        let mut audio = vec![];
+        let titles: Vec<_> = stream
+            .chunks(3)
+            .filter_map(|chunk| demuxer.push(chunk, &mut audio))
+            .collect();
+
+        assert_eq!(titles, vec!["Split".to_string()]);
+        assert_eq!(audio, [[1; 4], [2; 4]].concat());
+    }
+
+    #[test]
+    fn passes_everything_through_without_an_interval() {
+        let mut demuxer = IcyDemuxer::new(None);
+        let mut audio = vec![];
+
+        assert_eq!(demuxer.push(b"StreamTitle='x';", &mut audio), None);
+        assert_eq!(audio, b"StreamTitle='x';");
+    }
+}
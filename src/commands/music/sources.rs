@@ -0,0 +1,125 @@
+use std::{num::NonZeroUsize, sync::LazyLock, time::Duration};
+
+use lru::LruCache;
+use reqwest::header::CONTENT_TYPE;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::MIME_AUDIO_REGEX;
+
+use super::HTTP_CLIENT;
+
+/// urls ending in one of these are files we can stream ourselves, not pages for yt-dlp to dig
+/// through
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "ogg", "oga", "opus", "flac", "wav", "m4a", "aac", "mka", "aiff", "caf",
+];
+/// how long a url gets to answer a HEAD request before we assume it's a page
+const HEAD_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// what the HEAD requests found, by url, so the same link isn't asked about twice
+static CLASSIFIED: LazyLock<Mutex<LruCache<String, SourceKind>>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(512).expect("cache size isn't zero"),
+    ))
+});
+
+#[derive(Clone, Debug)]
+pub(super) enum SourceKind {
+    /// an audio file we can stream over http, with the content type the server gave it
+    Direct(Option<String>),
+    /// anything else, which yt-dlp has to work out
+    Page,
+}
+
+fn has_audio_extension(url: &Url) -> bool {
+    url.path_segments()
+        .and_then(Iterator::last)
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(_, extension)| {
+            AUDIO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+/// works out whether `url` points straight at an audio file, asking the server if the url
+/// itself doesn't make it obvious
+pub(super) async fn classify(url: &Url) -> SourceKind {
+    if !matches!(url.scheme(), "http" | "https") {
+        return SourceKind::Page;
+    }
+
+    if has_audio_extension(url) {
+        return SourceKind::Direct(None);
+    }
+
+    if let Some(kind) = CLASSIFIED.lock().await.get(url.as_str()) {
+        return kind.clone();
+    }
+
+    let content_type = HTTP_CLIENT
+        .head(url.as_str())
+        .timeout(HEAD_TIMEOUT)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .ok()
+        .and_then(|response| {
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string)
+        });
+
+    let kind = match content_type {
+        Some(content_type) if MIME_AUDIO_REGEX.is_match(&content_type) => {
+            SourceKind::Direct(Some(content_type))
+        }
+        _ => SourceKind::Page,
+    };
+
+    CLASSIFIED.lock().await.put(url.to_string(), kind.clone());
+
+    kind
+}
+
+/// the cheap half of [`classify`], for tracks that were queued without knowing: whether the
+/// url's extension gives it away. anything it isn't sure about goes through yt-dlp, which can play
+/// direct urls too, just slower
+pub(super) fn is_direct(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https") && has_audio_extension(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::has_audio_extension;
+
+    fn check(url: &str) -> bool {
+        has_audio_extension(&Url::parse(url).expect("test urls are valid"))
+    }
+
+    #[test]
+    fn audio_files() {
+        assert!(check("https://example.com/song.mp3"));
+        assert!(check("https://example.com/music/song.flac"));
+        assert!(check("https://example.com/SONG.OGG"));
+    }
+
+    #[test]
+    fn query_strings_are_ignored() {
+        assert!(check("https://cdn.example.com/song.opus?ex=1234&is=5678"));
+        assert!(check("https://example.com/song.m4a#t=30"));
+        assert!(!check("https://example.com/watch?v=song.mp3"));
+    }
+
+    #[test]
+    fn pages() {
+        assert!(!check("https://example.com/"));
+        assert!(!check("https://example.com/song"));
+        assert!(!check("https://example.com/mp3"));
+        assert!(!check("https://example.com/song.html"));
+        assert!(!check("https://example.com/song.mp3/"));
+    }
+}
@@ -1,10 +1,15 @@
+use std::time::Duration;
+
 use mongodb::{
-    bson::doc,
-    results::{InsertOneResult, UpdateResult},
+    bson::{doc, DateTime},
+    results::{DeleteResult, InsertOneResult, UpdateResult},
     Client,
 };
-use poise::serenity_prelude::{ChannelId, GuildId, MessageId};
+use poise::serenity_prelude::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use rgb::RGB;
 use serde_derive::{Deserialize, Serialize};
+use songbird::input::AuxMetadata;
+use url::Url;
 
 #[derive(Debug)]
 pub struct Database {
@@ -27,6 +32,242 @@ pub struct ReactionRolesMessage {
     pub message_id: MessageId,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MusicSettings {
+    pub guild_id: GuildId,
+    /// playback volume in percent
+    #[serde(default = "default_volume")]
+    pub volume: u8,
+    /// the highest volume anyone can set, in percent
+    #[serde(default = "default_max_volume")]
+    pub max_volume: u8,
+    /// how many tracks a single playlist link can add to the queue
+    #[serde(default = "default_max_playlist_tracks")]
+    pub max_playlist_tracks: u16,
+    /// members with this role can use the music admin commands
+    #[serde(default)]
+    pub dj_role: Option<RoleId>,
+    /// the most tracks the queue can hold at once
+    #[serde(default)]
+    pub max_queue_length: Option<u16>,
+    /// the longest track anyone can queue, in seconds
+    #[serde(default)]
+    pub max_track_duration: Option<u32>,
+    /// the most tracks one person can have in the queue at once
+    #[serde(default)]
+    pub max_tracks_per_user: Option<u16>,
+    #[serde(default = "default_allow_livestreams")]
+    pub allow_livestreams: bool,
+    /// whether the now playing message shows a progress bar that keeps itself up to date
+    #[serde(default)]
+    pub now_playing_progress: bool,
+    #[serde(default)]
+    pub announcements: Announcements,
+    /// the embed color to use when a track's thumbnail doesn't have a usable one, as 0xRRGGBB
+    #[serde(default)]
+    pub default_color: Option<u32>,
+    /// whether tracks get turned up or down to all sound about as loud as each other
+    #[serde(default)]
+    pub normalize: bool,
+    /// how loud normalized tracks should be, in LUFS
+    #[serde(default = "default_target_loudness")]
+    pub target_loudness: i8,
+    /// whether everyone with tracks in the queue takes turns, instead of first come first served
+    #[serde(default)]
+    pub fair_queue: bool,
+}
+
+/// where now playing messages get posted
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Announcements {
+    /// the chat built into whichever voice channel the bot is in
+    #[default]
+    VoiceChat,
+    Channel(ChannelId),
+    Off,
+}
+
+const fn default_volume() -> u8 {
+    100
+}
+
+const fn default_max_volume() -> u8 {
+    200
+}
+
+const fn default_max_playlist_tracks() -> u16 {
+    50
+}
+
+const fn default_allow_livestreams() -> bool {
+    true
+}
+
+const fn default_target_loudness() -> i8 {
+    -14
+}
+
+impl MusicSettings {
+    pub const fn new(guild_id: GuildId) -> Self {
+        Self {
+            guild_id,
+            volume: default_volume(),
+            max_volume: default_max_volume(),
+            max_playlist_tracks: default_max_playlist_tracks(),
+            dj_role: None,
+            max_queue_length: None,
+            max_track_duration: None,
+            max_tracks_per_user: None,
+            allow_livestreams: default_allow_livestreams(),
+            now_playing_progress: false,
+            announcements: Announcements::VoiceChat,
+            default_color: None,
+            normalize: false,
+            target_loudness: default_target_loudness(),
+            fair_queue: false,
+        }
+    }
+
+    /// where now playing messages should go while the bot is in `voice_channel`, if anywhere
+    pub const fn announcement_channel(&self, voice_channel: ChannelId) -> Option<ChannelId> {
+        match self.announcements {
+            Announcements::VoiceChat => Some(voice_channel),
+            Announcements::Channel(channel) => Some(channel),
+            Announcements::Off => None,
+        }
+    }
+
+    /// the guild's fallback embed color, if it has one
+    pub fn default_color(&self) -> Option<RGB<u8>> {
+        self.default_color.map(|color| {
+            let [_, r, g, b] = color.to_be_bytes();
+            RGB::new(r, g, b)
+        })
+    }
+}
+
+/// a track as it's stored in the database, with enough information to queue it again
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedTrack {
+    pub url: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub thumbnail: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// whether this is an internet radio station rather than a track
+    #[serde(default)]
+    pub radio: bool,
+    /// whether the url is an audio file that can be streamed without yt-dlp
+    #[serde(default)]
+    pub direct: bool,
+}
+
+impl SavedTrack {
+    pub fn new(url: &Url, metadata: &AuxMetadata, radio: bool, direct: bool) -> Self {
+        Self {
+            url: url.to_string(),
+            title: metadata.title.clone(),
+            artist: metadata.artist.clone(),
+            thumbnail: metadata.thumbnail.clone(),
+            duration_ms: metadata
+                .duration
+                .and_then(|d| u64::try_from(d.as_millis()).ok()),
+            radio,
+            direct,
+        }
+    }
+
+    /// turns this back into something that can be queued, or `None` if the url is broken
+    pub fn to_track(&self) -> Option<(Url, AuxMetadata)> {
+        let url = Url::parse(&self.url).ok()?;
+        let metadata = AuxMetadata {
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            thumbnail: self.thumbnail.clone(),
+            duration: self.duration_ms.map(Duration::from_millis),
+            source_url: Some(self.url.clone()),
+            ..Default::default()
+        };
+
+        Some((url, metadata))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedPlaylist {
+    pub guild_id: GuildId,
+    pub name: String,
+    pub owner_id: UserId,
+    pub tracks: Vec<SavedTrack>,
+}
+
+/// a queue that was playing when the bot last saved it, so it can be resumed after a restart
+#[derive(Serialize, Deserialize)]
+pub struct SavedQueue {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    /// how far into the first track playback had gotten
+    pub position_ms: u64,
+    pub tracks: Vec<SavedQueueTrack>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedQueueTrack {
+    #[serde(flatten)]
+    pub track: SavedTrack,
+    pub requester_id: UserId,
+    pub requester_name: String,
+    pub requester_avatar_url: String,
+    pub quick_leave: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub guild_id: GuildId,
+    #[serde(flatten)]
+    pub track: SavedTrack,
+    pub requester_id: Option<UserId>,
+    pub requester_name: Option<String>,
+    pub played_at: DateTime,
+}
+
+/// a file in the local music library
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LibraryTrack {
+    /// where the file is, relative to the library directory
+    pub path: String,
+    /// sha-256 of the file, in hex
+    pub hash: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// the size and modification time the file had when it was hashed. as long as these stay
+    /// the same, the file isn't read again
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_ms: Option<u64>,
+}
+
+/// how loud a track is, so it only has to be measured once
+#[derive(Serialize, Deserialize)]
+pub struct TrackLoudness {
+    pub url: String,
+    /// integrated loudness, in LUFS
+    pub integrated: f64,
+    /// the loudest sample in the track, where 1 is full scale
+    pub peak: f32,
+}
+
+/// an internet radio station saved under a name in a guild
+#[derive(Serialize, Deserialize)]
+pub struct RadioStation {
+    pub guild_id: GuildId,
+    pub name: String,
+    pub url: String,
+}
+
 impl Database {
     pub const fn new(client: Client, database: String) -> Self {
         Self { client, database }
@@ -72,4 +313,307 @@ impl Database {
 
         collection.replace_one(query, index).await
     }
+
+    /// gets a guild's music settings, falling back to the defaults if none have been saved
+    pub async fn get_music_settings(
+        &self,
+        guild_id: &GuildId,
+    ) -> Result<MusicSettings, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection("musicSettings");
+        let filter = doc! { "guild_id": guild_id.to_string() };
+
+        Ok(collection
+            .find_one(filter)
+            .await?
+            .unwrap_or_else(|| MusicSettings::new(*guild_id)))
+    }
+
+    pub async fn save_music_settings(
+        &self,
+        settings: &MusicSettings,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<MusicSettings>("musicSettings");
+        let query = doc! { "guild_id": settings.guild_id.to_string() };
+
+        collection.replace_one(query, settings).upsert(true).await
+    }
+
+    pub async fn get_playlist(
+        &self,
+        guild_id: &GuildId,
+        name: &str,
+    ) -> Result<Option<SavedPlaylist>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection("playlists");
+        let filter = doc! { "guild_id": guild_id.to_string(), "name": name };
+
+        collection.find_one(filter).await
+    }
+
+    pub async fn get_playlists(
+        &self,
+        guild_id: &GuildId,
+    ) -> Result<Vec<SavedPlaylist>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedPlaylist>("playlists");
+        let filter = doc! { "guild_id": guild_id.to_string() };
+
+        let mut cursor = collection.find(filter).sort(doc! { "name": 1 }).await?;
+        let mut playlists = vec![];
+        while cursor.advance().await? {
+            playlists.push(cursor.deserialize_current()?);
+        }
+
+        Ok(playlists)
+    }
+
+    pub async fn save_playlist(
+        &self,
+        playlist: &SavedPlaylist,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedPlaylist>("playlists");
+        let query = doc! { "guild_id": playlist.guild_id.to_string(), "name": &playlist.name };
+
+        collection.replace_one(query, playlist).upsert(true).await
+    }
+
+    pub async fn delete_playlist(
+        &self,
+        guild_id: &GuildId,
+        name: &str,
+    ) -> Result<DeleteResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedPlaylist>("playlists");
+        let query = doc! { "guild_id": guild_id.to_string(), "name": name };
+
+        collection.delete_one(query).await
+    }
+
+    pub async fn get_queues(&self) -> Result<Vec<SavedQueue>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedQueue>("activeQueues");
+
+        let mut cursor = collection.find(doc! {}).await?;
+        let mut queues = vec![];
+        while cursor.advance().await? {
+            queues.push(cursor.deserialize_current()?);
+        }
+
+        Ok(queues)
+    }
+
+    pub async fn save_queue(
+        &self,
+        queue: &SavedQueue,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedQueue>("activeQueues");
+        let query = doc! { "guild_id": queue.guild_id.to_string() };
+
+        collection.replace_one(query, queue).upsert(true).await
+    }
+
+    pub async fn delete_queue(
+        &self,
+        guild_id: &GuildId,
+    ) -> Result<DeleteResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<SavedQueue>("activeQueues");
+        let query = doc! { "guild_id": guild_id.to_string() };
+
+        collection.delete_one(query).await
+    }
+
+    pub async fn add_history(
+        &self,
+        entry: &HistoryEntry,
+    ) -> Result<InsertOneResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<HistoryEntry>("playHistory");
+
+        collection.insert_one(entry).await
+    }
+
+    /// gets a guild's most recently played tracks, newest first
+    pub async fn get_history(
+        &self,
+        guild_id: &GuildId,
+        limit: i64,
+    ) -> Result<Vec<HistoryEntry>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<HistoryEntry>("playHistory");
+        let filter = doc! { "guild_id": guild_id.to_string() };
+
+        let mut cursor = collection
+            .find(filter)
+            .sort(doc! { "played_at": -1 })
+            .limit(limit)
+            .await?;
+        let mut entries = vec![];
+        while cursor.advance().await? {
+            entries.push(cursor.deserialize_current()?);
+        }
+
+        Ok(entries)
+    }
+
+    pub async fn get_library(&self) -> Result<Vec<LibraryTrack>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<LibraryTrack>("musicLibrary");
+
+        let mut cursor = collection.find(doc! {}).await?;
+        let mut tracks = vec![];
+        while cursor.advance().await? {
+            tracks.push(cursor.deserialize_current()?);
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn save_library_track(
+        &self,
+        track: &LibraryTrack,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<LibraryTrack>("musicLibrary");
+        let query = doc! { "path": &track.path };
+
+        collection.replace_one(query, track).upsert(true).await
+    }
+
+    /// removes every track that isn't in `paths`, for files that were deleted since the last scan
+    pub async fn prune_library(
+        &self,
+        paths: &[String],
+    ) -> Result<DeleteResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<LibraryTrack>("musicLibrary");
+        let query = doc! { "path": { "$nin": paths } };
+
+        collection.delete_many(query).await
+    }
+
+    /// finds tracks whose title or artist contains `query`, ignoring case
+    pub async fn search_library(
+        &self,
+        query: &str,
+        limit: i64,
+    ) -> Result<Vec<LibraryTrack>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<LibraryTrack>("musicLibrary");
+        let pattern = regex::escape(query);
+        let filter = doc! {
+            "$or": [
+                { "title": { "$regex": &pattern, "$options": "i" } },
+                { "artist": { "$regex": &pattern, "$options": "i" } },
+            ]
+        };
+
+        let mut cursor = collection
+            .find(filter)
+            .sort(doc! { "title": 1 })
+            .limit(limit)
+            .await?;
+        let mut tracks = vec![];
+        while cursor.advance().await? {
+            tracks.push(cursor.deserialize_current()?);
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn random_library_track(
+        &self,
+    ) -> Result<Option<LibraryTrack>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<LibraryTrack>("musicLibrary");
+
+        let mut cursor = collection
+            .aggregate([doc! { "$sample": { "size": 1 } }])
+            .with_type::<LibraryTrack>()
+            .await?;
+
+        if cursor.advance().await? {
+            Ok(Some(cursor.deserialize_current()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn get_station(
+        &self,
+        guild_id: &GuildId,
+        name: &str,
+    ) -> Result<Option<RadioStation>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection("radioStations");
+        let filter = doc! { "guild_id": guild_id.to_string(), "name": name };
+
+        collection.find_one(filter).await
+    }
+
+    pub async fn get_stations(
+        &self,
+        guild_id: &GuildId,
+    ) -> Result<Vec<RadioStation>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<RadioStation>("radioStations");
+        let filter = doc! { "guild_id": guild_id.to_string() };
+
+        let mut cursor = collection.find(filter).sort(doc! { "name": 1 }).await?;
+        let mut stations = vec![];
+        while cursor.advance().await? {
+            stations.push(cursor.deserialize_current()?);
+        }
+
+        Ok(stations)
+    }
+
+    pub async fn save_station(
+        &self,
+        station: &RadioStation,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<RadioStation>("radioStations");
+        let query = doc! { "guild_id": station.guild_id.to_string(), "name": &station.name };
+
+        collection.replace_one(query, station).upsert(true).await
+    }
+
+    pub async fn delete_station(
+        &self,
+        guild_id: &GuildId,
+        name: &str,
+    ) -> Result<DeleteResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<RadioStation>("radioStations");
+        let query = doc! { "guild_id": guild_id.to_string(), "name": name };
+
+        collection.delete_one(query).await
+    }
+
+    pub async fn get_loudness(
+        &self,
+        url: &str,
+    ) -> Result<Option<TrackLoudness>, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection("trackLoudness");
+        let filter = doc! { "url": url };
+
+        collection.find_one(filter).await
+    }
+
+    pub async fn save_loudness(
+        &self,
+        loudness: &TrackLoudness,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let db = self.client.database(&self.database);
+        let collection = db.collection::<TrackLoudness>("trackLoudness");
+        let query = doc! { "url": &loudness.url };
+
+        collection.replace_one(query, loudness).upsert(true).await
+    }
 }
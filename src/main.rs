@@ -2,7 +2,13 @@
 #![warn(clippy::nursery)]
 #![warn(clippy::unwrap_used)]
 
-use commands::{music::music, reaction_roles::reaction_roles};
+use commands::{
+    music::{
+        library::scan_library, music, panel::handle_panel, persist_all_queues, restore_queues,
+        MusicSessions,
+    },
+    reaction_roles::reaction_roles,
+};
 use data::Database;
 use locale::Translator;
 use mongodb::Client;
@@ -10,12 +16,16 @@ use poise::builtins::register_globally;
 use poise::serenity_prelude::{
     CreateInteractionResponseFollowup, FullEvent, GatewayIntents, Interaction, Mentionable, RoleId,
 };
-use poise::{serenity_prelude as serenity, ApplicationContext, FrameworkError};
+use poise::{serenity_prelude as serenity, ApplicationContext, CreateReply, FrameworkError};
 use poise::{Framework, FrameworkOptions};
 use serde::Deserialize;
-use songbird::SerenityInit;
-use std::sync::{Arc, LazyLock};
+use songbird::{SerenityInit, Songbird};
+use std::{
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+};
 use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
@@ -32,6 +42,7 @@ use regex::Regex;
 pub struct Data {
     pub database: Arc<Database>,
     pub translator: Arc<Translator>,
+    pub music_sessions: Arc<MusicSessions>,
 }
 
 pub static ID_REGEX: LazyLock<Regex> =
@@ -44,6 +55,8 @@ struct Config {
     token: String,
     mongodb_url: String,
     mongodb_database: String,
+    /// a directory of our own music to index and play from
+    music_library: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -80,24 +93,48 @@ async fn main() -> Result<(), anyhow::Error> {
     let mongo_client = Client::with_uri_str(config.mongodb_url)
         .await
         .map_err(StartupError::Database)?;
+    let database = Arc::new(Database::new(mongo_client, config.mongodb_database));
+    let songbird = Songbird::serenity();
+
+    let setup_database = database.clone();
+    let setup_songbird = songbird.clone();
+    let music_library = config.music_library;
 
     let framework = Framework::builder()
         .options(FrameworkOptions {
             commands: vec![reaction_roles(), music()],
-            event_handler: |ctx, event, _framework, _data| {
+            event_handler: |ctx, event, _framework, data| {
                 Box::pin(async move {
                     if let FullEvent::InteractionCreate { interaction } = event {
                         handle_reaction_roles(ctx, interaction).await?;
+                        handle_panel(ctx, data, interaction).await?;
                     }
                     Ok(())
                 })
             },
             on_error: |err| {
                 Box::pin(async move {
-                    if let FrameworkError::Command { error, ctx, .. } = err {
-                        tracing::error!(
-                            "error running command: {error:?} \n context for debugging: {ctx:?}"
-                        );
+                    match err {
+                        FrameworkError::Command { error, ctx, .. } => {
+                            tracing::error!(
+                                "error running command: {error:?} \n context for debugging: {ctx:?}"
+                            );
+                        }
+                        // poise doesn't tell the user anything by itself
+                        FrameworkError::MissingUserPermissions { ctx, .. } => {
+                            let content = local_get(
+                                &ctx.data().translator,
+                                "commands_missingpermissions",
+                                ctx.locale().unwrap_or("en-US"),
+                            );
+                            if let Err(why) = ctx
+                                .send(CreateReply::default().content(content).ephemeral(true))
+                                .await
+                            {
+                                tracing::warn!("problem replying about permissions: {:?}", why);
+                            }
+                        }
+                        _ => {}
                     }
                 })
             },
@@ -106,10 +143,25 @@ async fn main() -> Result<(), anyhow::Error> {
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data {
-                    database: Arc::new(Database::new(mongo_client, config.mongodb_database)),
+                let data = Data {
+                    database: setup_database,
                     translator: Arc::new(translator),
-                })
+                    music_sessions: Arc::new(MusicSessions::default()),
+                };
+
+                tokio::spawn(restore_queues(
+                    setup_songbird,
+                    ctx.http.clone(),
+                    ctx.cache.clone(),
+                    data.music_sessions.clone(),
+                    data.database.clone(),
+                ));
+
+                if let Some(root) = music_library {
+                    tokio::spawn(scan_library(data.database.clone(), root));
+                }
+
+                Ok(data)
             })
         })
         .build();
@@ -118,10 +170,23 @@ async fn main() -> Result<(), anyhow::Error> {
         config.token,
         GatewayIntents::non_privileged() | GatewayIntents::GUILD_VOICE_STATES,
     )
-    .register_songbird()
+    .register_songbird_with(songbird.clone())
     .framework(framework)
     .await?;
 
+    let shard_manager = client.shard_manager.clone();
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+
+        tracing::info!("shutting down, saving active queues");
+        persist_all_queues(&songbird, &database).await;
+        shard_manager.shutdown_all().await;
+    });
+
     Ok(client.start().await?)
 }
 
@@ -144,9 +209,10 @@ async fn handle_reaction_roles(
     interaction: &Interaction,
 ) -> Result<(), Error> {
     if let Some(mut component) = interaction.clone().message_component() {
-        component.defer(&ctx).await?;
-
+        // other components (pagination, pickers) are handled by their own collectors
         if let Some(captures) = ID_REGEX.captures(&component.data.custom_id) {
+            component.defer(&ctx).await?;
+
             if let Ok(role_id) = captures[1].parse::<RoleId>() {
                 let role = role_id.mention();
                 if let Some(ref mut member) = component.member {